@@ -1,3 +1,5 @@
+use crate::progress::{JobProgress, JobStage};
+
 pub const MESHTASTIC_API_URL: &str = "https://api.meshtastic.org";
 
 pub mod boards {
@@ -134,10 +136,15 @@ pub async fn fetch_firmware_releases() -> Result<firmware::ListFirmwareResponse,
     Ok(list_firmware_response)
 }
 
-pub async fn fetch_firmware_bundle(firmware_zip_url: String) -> Result<bytes::Bytes, String> {
+pub async fn fetch_firmware_bundle(
+    firmware_zip_url: String,
+    progress: &mut JobProgress,
+) -> Result<bytes::Bytes, String> {
     log::info!("Downloading firmware from {}", firmware_zip_url.clone());
 
-    let response = match reqwest::get(firmware_zip_url.clone()).await {
+    progress.start_stage(JobStage::Download);
+
+    let mut response = match reqwest::get(firmware_zip_url.clone()).await {
         Ok(response) => response,
         Err(e) => {
             log::error!(
@@ -155,23 +162,34 @@ pub async fn fetch_firmware_bundle(firmware_zip_url: String) -> Result<bytes::By
 
     log::info!("Successfully created request to fetch firmware");
 
-    let bytes = match response.bytes().await {
-        Ok(bytes) => bytes,
-        Err(e) => {
-            log::error!(
-                "Error while downloading firmware at URL {}: {}",
-                firmware_zip_url,
-                e.to_string()
-            );
+    // Unknown when the server doesn't send a Content-Length, leaving the stage unsized
+    let content_length = response.content_length().unwrap_or(0) as usize;
+    let mut bytes = Vec::with_capacity(content_length);
 
-            return Err(format!(
-                "Error while downloading firmware at URL {}: {}",
-                firmware_zip_url, e
-            ));
-        }
-    };
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                log::error!(
+                    "Error while downloading firmware at URL {}: {}",
+                    firmware_zip_url,
+                    e.to_string()
+                );
+
+                return Err(format!(
+                    "Error while downloading firmware at URL {}: {}",
+                    firmware_zip_url, e
+                ));
+            }
+        };
+
+        bytes.extend_from_slice(&chunk);
+        progress.update(bytes.len(), content_length);
+        progress.check_cancelled()?;
+    }
 
     log::info!("Successfully downloaded {} bytes", bytes.len());
 
-    Ok(bytes)
+    Ok(bytes::Bytes::from(bytes))
 }
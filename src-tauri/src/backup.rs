@@ -0,0 +1,606 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::api::boards::Board;
+use crate::chip::EspChipInfo;
+use crate::flasher::EspConnectionConfig;
+use crate::progress::{JobProgress, JobStage};
+use crate::session::{EspFlashSession, EspImage};
+use crate::uf2::{
+    copy_uf2_file, get_firmware_usb_ids, get_uf2_drive, list_usb_serial_ports, parse_uf2_blocks,
+    read_uf2_bootloader_info, wait_for_uf2_reboot, Uf2BootloaderInfo,
+};
+
+/// Image of the application flash that UF2 bootloaders expose on their drive
+const UF2_CURRENT_FILE_NAME: &str = "CURRENT.UF2";
+
+const BACKUP_READ_BLOCK_SIZE: u32 = 0x1000;
+const BACKUP_READ_MAX_IN_FLIGHT: u32 = 64;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupMetadata {
+    pub file_name: String,
+    pub created_at: u64, // Seconds since Unix epoch
+    pub hw_model_slug: String,
+    pub chip: String,
+    pub mac_address: String, // Empty for UF2 backups, since the bootloader doesn't report it
+    pub flash_size: String,
+    pub flash_size_bytes: u32,
+    pub uf2_bootloader: Option<Uf2BootloaderInfo>, // UF2 backups only
+}
+
+fn get_backup_metadata_path(backup_file_path: &Path) -> PathBuf {
+    backup_file_path.with_extension("json")
+}
+
+/// Reads the device's entire flash into a timestamped image in the backup directory
+pub async fn backup_esp32(
+    backup_directory: &Path,
+    temp_directory: PathBuf,
+    upload_port: String,
+    board: &Board,
+    connection_config: &EspConnectionConfig,
+    progress: &mut JobProgress,
+) -> Result<BackupMetadata, String> {
+    let mut session = EspFlashSession::connect(
+        temp_directory,
+        &upload_port,
+        board,
+        0,
+        connection_config,
+        progress,
+    )
+    .await?;
+
+    let backup_metadata =
+        backup_esp32_session(&mut session, backup_directory, board, progress).await?;
+
+    session.reset(progress)?;
+
+    Ok(backup_metadata)
+}
+
+/// Backs up the device over an already open session, without resetting it
+pub async fn backup_esp32_session(
+    session: &mut EspFlashSession,
+    backup_directory: &Path,
+    board: &Board,
+    progress: &mut JobProgress,
+) -> Result<BackupMetadata, String> {
+    let upload_port = session.upload_port().clone();
+    let chip_info = session.chip_info().clone();
+
+    let created_at = get_created_at()?;
+
+    let backup_file_name = format!(
+        "backup-{}-{}-{}.bin",
+        created_at,
+        chip_info.chip,
+        chip_info.mac_address.replace(':', "")
+    );
+
+    let backup_file_path = backup_directory.join(backup_file_name.clone());
+
+    log::info!(
+        "Backing up {} of flash on port {} to {}",
+        chip_info.flash_size,
+        upload_port,
+        backup_file_path.display()
+    );
+
+    // Flash readback doesn't report progress, so the stage jumps from empty to done
+    progress.start_stage(JobStage::Backup);
+
+    match session.flasher()?.read_flash(
+        0,
+        chip_info.flash_size_bytes,
+        BACKUP_READ_BLOCK_SIZE,
+        BACKUP_READ_MAX_IN_FLIGHT,
+        backup_file_path.clone(),
+    ) {
+        Ok(_) => (),
+        Err(e) => {
+            log::error!("Error while reading flash on port {}: {}", upload_port, e);
+            return Err(format!(
+                "Error while reading flash on port {}: {}",
+                upload_port, e
+            ));
+        }
+    };
+
+    progress.update(
+        chip_info.flash_size_bytes as usize,
+        chip_info.flash_size_bytes as usize,
+    );
+
+    let backup_metadata = BackupMetadata {
+        file_name: backup_file_name,
+        created_at,
+        hw_model_slug: board.hw_model_slug.clone(),
+        chip: chip_info.chip,
+        mac_address: chip_info.mac_address,
+        flash_size: chip_info.flash_size,
+        flash_size_bytes: chip_info.flash_size_bytes,
+        uf2_bootloader: None,
+    };
+
+    write_backup_metadata(&backup_file_path, &backup_metadata).await?;
+
+    log::info!("Successfully backed up device: {:?}", backup_metadata);
+
+    Ok(backup_metadata)
+}
+
+fn get_created_at() -> Result<u64, String> {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => Ok(duration.as_secs()),
+        Err(e) => {
+            log::error!("Error while reading system time: {}", e);
+            Err(format!("Error while reading system time: {}", e))
+        }
+    }
+}
+
+/// Copies the application flash image a UF2 bootloader exposes as CURRENT.UF2 into a
+/// timestamped image in the backup directory
+pub async fn backup_uf2_device(
+    backup_directory: &Path,
+    drive_path: &Path,
+    board: &Board,
+    progress: &mut JobProgress,
+) -> Result<BackupMetadata, String> {
+    let bootloader_info = read_uf2_bootloader_info(drive_path)?;
+    let current_file_path = drive_path.join(UF2_CURRENT_FILE_NAME);
+
+    let created_at = get_created_at()?;
+
+    let backup_file_name = format!("backup-{}-{}.uf2", created_at, board.hw_model_slug);
+    let backup_file_path = backup_directory.join(backup_file_name.clone());
+
+    log::info!(
+        "Backing up {} to {}",
+        current_file_path.display(),
+        backup_file_path.display()
+    );
+
+    // The bootloader generates the file while it is read, so there's no size to report up front
+    progress.start_stage(JobStage::Backup);
+
+    let current_image = match tokio::fs::read(&current_file_path).await {
+        Ok(current_image) => current_image,
+        Err(e) => {
+            log::error!("Error while reading {}: {}", current_file_path.display(), e);
+
+            return Err(format!(
+                "Error while reading {}: {}",
+                current_file_path.display(),
+                e
+            ));
+        }
+    };
+
+    // A truncated read would only be noticed when restoring, which is too late
+    let flash_size_bytes: usize = parse_uf2_blocks(&current_image)?
+        .iter()
+        .map(|block| block.data.len())
+        .sum();
+
+    match tokio::fs::write(&backup_file_path, &current_image).await {
+        Ok(_) => (),
+        Err(e) => {
+            log::error!(
+                "Error while writing backup to {}: {}",
+                backup_file_path.display(),
+                e
+            );
+
+            return Err(format!(
+                "Error while writing backup to {}: {}",
+                backup_file_path.display(),
+                e
+            ));
+        }
+    };
+
+    progress.update(current_image.len(), current_image.len());
+
+    let backup_metadata = BackupMetadata {
+        file_name: backup_file_name,
+        created_at,
+        hw_model_slug: board.hw_model_slug.clone(),
+        chip: board.architecture.clone(),
+        mac_address: String::new(),
+        flash_size: format!("{}KB", flash_size_bytes / 1024),
+        flash_size_bytes: flash_size_bytes as u32,
+        uf2_bootloader: Some(bootloader_info),
+    };
+
+    write_backup_metadata(&backup_file_path, &backup_metadata).await?;
+
+    log::info!("Successfully backed up device: {:?}", backup_metadata);
+
+    Ok(backup_metadata)
+}
+
+async fn write_backup_metadata(
+    backup_file_path: &Path,
+    backup_metadata: &BackupMetadata,
+) -> Result<(), String> {
+    let metadata_path = get_backup_metadata_path(backup_file_path);
+
+    let metadata_json = match serde_json::to_string_pretty(backup_metadata) {
+        Ok(metadata_json) => metadata_json,
+        Err(e) => {
+            log::error!("Error while serializing backup metadata: {}", e);
+            return Err(format!("Error while serializing backup metadata: {}", e));
+        }
+    };
+
+    match tokio::fs::write(&metadata_path, metadata_json).await {
+        Ok(_) => (),
+        Err(e) => {
+            log::error!(
+                "Error while writing backup metadata to {}: {}",
+                metadata_path.display(),
+                e
+            );
+
+            return Err(format!(
+                "Error while writing backup metadata to {}: {}",
+                metadata_path.display(),
+                e
+            ));
+        }
+    };
+
+    Ok(())
+}
+
+async fn read_backup_metadata(metadata_path: &Path) -> Result<BackupMetadata, String> {
+    let metadata_json = match tokio::fs::read_to_string(metadata_path).await {
+        Ok(metadata_json) => metadata_json,
+        Err(e) => {
+            log::error!(
+                "Error while reading backup metadata at {}: {}",
+                metadata_path.display(),
+                e
+            );
+
+            return Err(format!(
+                "Error while reading backup metadata at {}: {}",
+                metadata_path.display(),
+                e
+            ));
+        }
+    };
+
+    match serde_json::from_str(&metadata_json) {
+        Ok(backup_metadata) => Ok(backup_metadata),
+        Err(e) => {
+            log::error!(
+                "Error while parsing backup metadata at {}: {}",
+                metadata_path.display(),
+                e
+            );
+
+            Err(format!(
+                "Error while parsing backup metadata at {}: {}",
+                metadata_path.display(),
+                e
+            ))
+        }
+    }
+}
+
+pub async fn list_backups(backup_directory: &Path) -> Result<Vec<BackupMetadata>, String> {
+    let mut entries = match tokio::fs::read_dir(backup_directory).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::error!(
+                "Error while reading backup directory {}: {}",
+                backup_directory.display(),
+                e
+            );
+
+            return Err(format!(
+                "Error while reading backup directory {}: {}",
+                backup_directory.display(),
+                e
+            ));
+        }
+    };
+
+    let mut backups = Vec::new();
+
+    loop {
+        let entry = match entries.next_entry().await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(e) => {
+                log::error!("Error while reading backup directory entry: {}", e);
+                return Err(format!("Error while reading backup directory entry: {}", e));
+            }
+        };
+
+        let path = entry.path();
+
+        if path.extension().is_some_and(|ext| ext == "json") {
+            // Skip unreadable metadata rather than hiding every other backup
+            match read_backup_metadata(&path).await {
+                Ok(backup_metadata) => backups.push(backup_metadata),
+                Err(e) => log::warn!("Skipping backup metadata {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    backups.sort_by_key(|b| std::cmp::Reverse(b.created_at));
+
+    Ok(backups)
+}
+
+/// Resolves a backup chosen by the front end, refusing anything that isn't one of the backups
+/// listed in the backup directory
+async fn get_backup_file_path(
+    backup_directory: &Path,
+    backup_file_name: &String,
+) -> Result<PathBuf, String> {
+    if backup_file_name.contains(['/', '\\']) || backup_file_name.contains("..") {
+        log::error!("Invalid backup file name: {}", backup_file_name);
+        return Err(format!("Invalid backup file name: {}", backup_file_name));
+    }
+
+    let is_listed = list_backups(backup_directory)
+        .await?
+        .iter()
+        .any(|backup_metadata| backup_metadata.file_name == *backup_file_name);
+
+    if !is_listed {
+        log::error!(
+            "Backup {} not found in {}",
+            backup_file_name,
+            backup_directory.display()
+        );
+
+        return Err(format!(
+            "Backup {} not found in {}",
+            backup_file_name,
+            backup_directory.display()
+        ));
+    }
+
+    Ok(backup_directory.join(backup_file_name))
+}
+
+/// Writes a backup image back over the device's entire flash
+pub async fn restore_esp32_backup(
+    backup_directory: &Path,
+    temp_directory: PathBuf,
+    upload_port: String,
+    board: &Board,
+    backup_file_name: String,
+    connection_config: &EspConnectionConfig,
+    progress: &mut JobProgress,
+) -> Result<EspChipInfo, String> {
+    let backup_file_path = get_backup_file_path(backup_directory, &backup_file_name).await?;
+
+    let backup_metadata =
+        read_backup_metadata(&get_backup_metadata_path(&backup_file_path)).await?;
+
+    log::info!(
+        "Restoring backup {:?} to port {}",
+        backup_metadata,
+        upload_port
+    );
+
+    let backup_binary = match tokio::fs::read(&backup_file_path).await {
+        Ok(backup_binary) => backup_binary,
+        Err(e) => {
+            log::error!(
+                "Error while reading backup at {}: {}",
+                backup_file_path.display(),
+                e
+            );
+
+            return Err(format!(
+                "Error while reading backup at {}: {}",
+                backup_file_path.display(),
+                e
+            ));
+        }
+    };
+
+    let mut session = EspFlashSession::connect(
+        temp_directory,
+        &upload_port,
+        board,
+        backup_metadata.flash_size_bytes,
+        connection_config,
+        progress,
+    )
+    .await?;
+
+    // Check the backup matches the connected chip before overwriting anything
+    let chip_info = session.chip_info().clone();
+
+    if chip_info.chip != backup_metadata.chip {
+        log::error!(
+            "Backup {} was taken from chip {} but the connected device is chip {}",
+            backup_metadata.file_name,
+            backup_metadata.chip,
+            chip_info.chip
+        );
+
+        return Err(format!(
+            "Backup {} was taken from chip {} but the connected device is chip {}",
+            backup_metadata.file_name, backup_metadata.chip, chip_info.chip
+        ));
+    }
+
+    if chip_info.mac_address != backup_metadata.mac_address {
+        log::warn!(
+            "Restoring backup taken from device {} onto device {}",
+            backup_metadata.mac_address,
+            chip_info.mac_address
+        );
+    }
+
+    session
+        .write_images(
+            &[EspImage {
+                name: backup_metadata.file_name.clone(),
+                offset: 0x0000_0000,
+                data: &backup_binary,
+            }],
+            true,
+            progress,
+        )
+        .await?;
+
+    let flash_report = session.reset(progress)?;
+
+    log::info!("Successfully restored backup {}", backup_metadata.file_name);
+
+    Ok(flash_report.chip_info)
+}
+
+/// Checks that a UF2 backup was taken from a board with the same bootloader Board-ID as the
+/// one mounted now, since CURRENT.UF2 holds board-specific firmware
+fn check_uf2_backup_board(
+    backup_metadata: &BackupMetadata,
+    bootloader_info: &Uf2BootloaderInfo,
+) -> Result<(), String> {
+    let backup_board_id = match &backup_metadata.uf2_bootloader {
+        Some(backup_bootloader_info) => backup_bootloader_info.board_id.as_deref(),
+        None => {
+            log::error!("Backup {} is not a UF2 backup", backup_metadata.file_name);
+            return Err(format!(
+                "Backup {} was not taken from a UF2 device",
+                backup_metadata.file_name
+            ));
+        }
+    };
+
+    if backup_board_id.is_none() || backup_board_id != bootloader_info.board_id.as_deref() {
+        log::error!(
+            "Backup {} was taken from board {:?} but the connected device is board {:?}",
+            backup_metadata.file_name,
+            backup_board_id,
+            bootloader_info.board_id
+        );
+
+        return Err(format!(
+            "Backup {} was taken from board {} but the connected device is board {}",
+            backup_metadata.file_name,
+            backup_board_id.unwrap_or("unknown"),
+            bootloader_info.board_id.as_deref().unwrap_or("unknown")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Copies a UF2 backup back onto the device's bootloader drive and waits for the restored
+/// firmware to come back, returning its serial port
+pub async fn restore_uf2_backup(
+    backup_directory: &Path,
+    upload_port: String,
+    board: &Board,
+    backup_file_name: String,
+    progress: &mut JobProgress,
+) -> Result<String, String> {
+    let backup_file_path = get_backup_file_path(backup_directory, &backup_file_name).await?;
+
+    let backup_metadata =
+        read_backup_metadata(&get_backup_metadata_path(&backup_file_path)).await?;
+
+    log::info!(
+        "Restoring backup {:?} to port {}",
+        backup_metadata,
+        upload_port
+    );
+
+    // Read before touching, since the firmware's port disappears into the bootloader
+    let firmware_usb_ids = get_firmware_usb_ids(&upload_port, board)?;
+
+    let drive_path = get_uf2_drive(&upload_port, progress).await?;
+
+    // Check the backup matches the connected board before overwriting anything
+    check_uf2_backup_board(&backup_metadata, &read_uf2_bootloader_info(&drive_path)?)?;
+
+    let bootloader_ports = list_usb_serial_ports()?;
+
+    copy_uf2_file(
+        backup_file_name,
+        backup_file_path,
+        drive_path.clone(),
+        progress,
+    )
+    .await?;
+
+    let firmware_port =
+        wait_for_uf2_reboot(&drive_path, &firmware_usb_ids, &bootloader_ports, progress).await?;
+
+    log::info!("Successfully restored backup {}", backup_metadata.file_name);
+
+    Ok(firmware_port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{build_uf2_backup_metadata, build_uf2_bootloader_info};
+
+    #[test]
+    fn accepts_backup_from_same_board() {
+        let backup_metadata = build_uf2_backup_metadata(Some("nRF52840-RAK4631-Rev1"));
+
+        assert!(check_uf2_backup_board(
+            &backup_metadata,
+            &build_uf2_bootloader_info(Some("nRF52840-RAK4631-Rev1"))
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn rejects_backup_from_other_board() {
+        let backup_metadata = build_uf2_backup_metadata(Some("nRF52840-RAK4631-Rev1"));
+
+        assert!(check_uf2_backup_board(
+            &backup_metadata,
+            &build_uf2_bootloader_info(Some("nRF52840-TECHO-Rev1"))
+        )
+        .is_err());
+        assert!(
+            check_uf2_backup_board(&backup_metadata, &build_uf2_bootloader_info(None)).is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_esp32_and_unidentified_backups() {
+        let esp32_backup_metadata = BackupMetadata {
+            uf2_bootloader: None,
+            ..build_uf2_backup_metadata(None)
+        };
+
+        assert!(
+            check_uf2_backup_board(&esp32_backup_metadata, &build_uf2_bootloader_info(None))
+                .is_err()
+        );
+        assert!(check_uf2_backup_board(
+            &build_uf2_backup_metadata(None),
+            &build_uf2_bootloader_info(None)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn reads_metadata_written_before_uf2_backups() {
+        let backup_metadata: BackupMetadata = serde_json::from_str(
+            r#"{"fileName":"backup-1700000000-esp32-aabbccddeeff.bin","createdAt":1700000000,"hwModelSlug":"TBEAM","chip":"esp32","macAddress":"aa:bb:cc:dd:ee:ff","flashSize":"4MB","flashSizeBytes":4194304}"#,
+        )
+        .unwrap();
+
+        assert!(backup_metadata.uf2_bootloader.is_none());
+    }
+}
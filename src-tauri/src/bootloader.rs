@@ -0,0 +1,463 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::api::boards::Board;
+use crate::dfu::{enter_serial_dfu, read_dfu_package, send_dfu_package};
+use crate::fs::get_resource_file_path;
+use crate::progress::{JobProgress, JobStage};
+use crate::uf2::{
+    get_firmware_usb_ids, get_uf2_drive, list_uf2_drive_paths, read_uf2_bootloader_info,
+    wait_for_new_uf2_drive, wait_for_uf2_drive_unmount, Uf2BootloaderInfo,
+};
+
+type Version = (u32, u32, u32);
+
+// Every Meshtastic nRF52840 release links with the Adafruit nRF52 core's nrf52840_s140_v6.ld,
+// which places the app after an S140 6.1.1 SoftDevice, so the requirement is the same for every
+// firmware version. The app calls into the SoftDevice, so only 6.x releases from 6.1.1 on are
+// compatible. Bootloader releases and the SoftDevice each one ships with are listed at
+// https://github.com/adafruit/Adafruit_nRF52_Bootloader/releases
+const MIN_NRF_BOOTLOADER_VERSION: Version = (0, 6, 1);
+const REQUIRED_SOFT_DEVICE: &str = "S140";
+const MIN_SOFT_DEVICE_VERSION: Version = (6, 1, 1);
+
+/// Adafruit bootloader release whose packages are bundled under resources/bootloaders
+const NRF_BOOTLOADER_PACKAGE_VERSION: &str = "0.9.2";
+
+/// Adafruit bootloader board names, by the platformio targets that run that board's bootloader
+const NRF_BOOTLOADER_BOARDS: [(&str, &str); 4] = [
+    ("rak4631", "rak4631"),
+    ("rak4631_eink", "rak4631"),
+    ("feather_diy", "feather_nrf52840_express"),
+    ("pca10059_diy_eink", "pca10059"),
+];
+
+/// What has to be replaced before firmware can be flashed
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NrfBootloaderUpdate {
+    None,
+    /// Bootloader only, with the `update-*_nosd.uf2` package copied to the UF2 drive
+    Bootloader,
+    /// SoftDevice and bootloader together, with the `*_s140_6.1.1.zip` package over serial DFU
+    SoftDeviceAndBootloader,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NrfBootloaderStatus {
+    pub info: Uf2BootloaderInfo,
+    pub required_bootloader_version: String,
+    pub required_soft_device: String,
+    pub required_update: NrfBootloaderUpdate,
+}
+
+/// Parses "0.6.1" or "6.1" style versions, treating missing components as 0
+fn parse_version(version: &str) -> Option<Version> {
+    let mut components = version.trim().split('.').map(|c| c.parse::<u32>());
+
+    let major = components.next()?.ok()?;
+    let minor = components.next().unwrap_or(Ok(0)).ok()?;
+    let patch = components.next().unwrap_or(Ok(0)).ok()?;
+
+    Some((major, minor, patch))
+}
+
+/// Parses INFO_UF2.TXT's "S140 version 6.1.1" into its name and version
+fn parse_soft_device(soft_device: &str) -> Option<(String, Version)> {
+    let mut parts = soft_device.split_whitespace();
+
+    let name = parts.next()?.to_string();
+    let version = parse_version(parts.last()?)?;
+
+    Some((name, version))
+}
+
+fn format_version(version: &Version) -> String {
+    format!("{}.{}.{}", version.0, version.1, version.2)
+}
+
+/// Compares the bootloader on a UF2 drive against what Meshtastic firmware needs
+pub fn get_nrf_bootloader_status(info: &Uf2BootloaderInfo) -> NrfBootloaderStatus {
+    // Anything that can't be read is treated as too old, since flashing over an incompatible
+    // bootloader is what leaves boards boot looping
+    let soft_device_compatible = match info.soft_device.as_deref().and_then(parse_soft_device) {
+        Some((name, version)) => {
+            name.eq_ignore_ascii_case(REQUIRED_SOFT_DEVICE)
+                && version.0 == MIN_SOFT_DEVICE_VERSION.0
+                && version >= MIN_SOFT_DEVICE_VERSION
+        }
+        None => false,
+    };
+
+    let bootloader_compatible = match info.bootloader_version.as_deref().and_then(parse_version) {
+        Some(version) => version >= MIN_NRF_BOOTLOADER_VERSION,
+        None => false,
+    };
+
+    let required_update = match (soft_device_compatible, bootloader_compatible) {
+        (false, _) => NrfBootloaderUpdate::SoftDeviceAndBootloader,
+        (true, false) => NrfBootloaderUpdate::Bootloader,
+        (true, true) => NrfBootloaderUpdate::None,
+    };
+
+    let status = NrfBootloaderStatus {
+        info: info.clone(),
+        required_bootloader_version: format_version(&MIN_NRF_BOOTLOADER_VERSION),
+        required_soft_device: format!(
+            "{} {}",
+            REQUIRED_SOFT_DEVICE,
+            format_version(&MIN_SOFT_DEVICE_VERSION)
+        ),
+        required_update,
+    };
+
+    log::info!("nRF52 bootloader status: {:?}", status);
+
+    status
+}
+
+/// Touches the device on `upload_port` into its bootloader and checks what it runs
+///
+/// The device is left in its bootloader, so a following flash or bootloader update should use
+/// its drive or the bootloader's serial port.
+pub async fn read_nrf_bootloader_status(
+    upload_port: &String,
+    progress: &mut JobProgress,
+) -> Result<NrfBootloaderStatus, String> {
+    let drive_path = get_uf2_drive(upload_port, progress).await?;
+    let info = read_uf2_bootloader_info(&drive_path)?;
+
+    Ok(get_nrf_bootloader_status(&info))
+}
+
+/// Blocks flashing firmware the bootloader would reject or boot loop on
+pub fn check_nrf_bootloader_compatible(status: &NrfBootloaderStatus) -> Result<(), String> {
+    let installed = format!(
+        "bootloader {} with SoftDevice {}",
+        status
+            .info
+            .bootloader_version
+            .as_deref()
+            .unwrap_or("unknown"),
+        status.info.soft_device.as_deref().unwrap_or("unknown")
+    );
+
+    match status.required_update {
+        NrfBootloaderUpdate::None => Ok(()),
+        NrfBootloaderUpdate::Bootloader => {
+            log::error!(
+                "Firmware requires bootloader {}, device has {}",
+                status.required_bootloader_version,
+                installed
+            );
+
+            Err(format!(
+                "This firmware requires bootloader {} or newer, but the device has {}. Update the bootloader before flashing, or skip the bootloader check if you have confirmed it is compatible.",
+                status.required_bootloader_version, installed
+            ))
+        }
+        NrfBootloaderUpdate::SoftDeviceAndBootloader => {
+            log::error!(
+                "Firmware requires SoftDevice {}, device has {}",
+                status.required_soft_device,
+                installed
+            );
+
+            Err(format!(
+                "This firmware requires SoftDevice {} and bootloader {} or newer, but the device has {}. Update the SoftDevice and bootloader before flashing, or skip the bootloader check if you have confirmed they are compatible.",
+                status.required_soft_device, status.required_bootloader_version, installed
+            ))
+        }
+    }
+}
+
+/// Name of the bundled Adafruit package that performs `update` on `board`
+pub fn get_nrf_bootloader_package_file_name(
+    board: &Board,
+    update: &NrfBootloaderUpdate,
+) -> Result<String, String> {
+    let bootloader_board = match NRF_BOOTLOADER_BOARDS
+        .iter()
+        .find(|(platformio_target, _)| board.platformio_target == *platformio_target)
+    {
+        Some((_, bootloader_board)) => bootloader_board,
+        None => {
+            log::error!(
+                "No bootloader update package is bundled for board {}",
+                board.platformio_target
+            );
+
+            return Err(format!(
+                "No bootloader update package is bundled for board {}. Update the bootloader with the package from the board's manufacturer instead.",
+                board.hw_model_slug
+            ));
+        }
+    };
+
+    match update {
+        NrfBootloaderUpdate::None => {
+            log::error!("No bootloader update is needed");
+            Err("No bootloader update is needed".to_string())
+        }
+        NrfBootloaderUpdate::Bootloader => Ok(format!(
+            "update-{}_bootloader-{}_nosd.uf2",
+            bootloader_board, NRF_BOOTLOADER_PACKAGE_VERSION
+        )),
+        NrfBootloaderUpdate::SoftDeviceAndBootloader => Ok(format!(
+            "{}_bootloader-{}_s140_6.1.1.zip",
+            bootloader_board, NRF_BOOTLOADER_PACKAGE_VERSION
+        )),
+    }
+}
+
+/// Installs the bundled package for `update` from `bootloader_directory`, returning the new
+/// bootloader info when the device's drive comes back with it
+///
+/// Bootloader updates are copied to the UF2 drive behind `upload_port`. SoftDevice updates are
+/// sent over serial DFU, touching `upload_port` into the bootloader first if it is still the
+/// firmware's port; the device can't be told apart from other mounted drives afterwards, so no
+/// info is returned for them.
+pub async fn update_nrf_bootloader(
+    upload_port: &String,
+    board: &Board,
+    update: &NrfBootloaderUpdate,
+    bootloader_directory: &Path,
+    progress: &mut JobProgress,
+) -> Result<Option<Uf2BootloaderInfo>, String> {
+    let package_file_name = get_nrf_bootloader_package_file_name(board, update)?;
+    let package_file_path = get_resource_file_path(bootloader_directory, &package_file_name)?;
+
+    log::info!(
+        "Updating bootloader on port {} with {}",
+        upload_port,
+        package_file_path.display()
+    );
+
+    if let NrfBootloaderUpdate::SoftDeviceAndBootloader = update {
+        // Read before touching the device, so a bad package doesn't strand it in the bootloader
+        let package = read_dfu_package(&package_file_path).await?;
+
+        progress.start_stage(JobStage::Connect);
+
+        let firmware_usb_ids = get_firmware_usb_ids(upload_port, board)?;
+        let dfu_port = enter_serial_dfu(upload_port, &firmware_usb_ids, progress).await?;
+
+        send_dfu_package(&dfu_port, &package, progress).await?;
+
+        log::info!("Sent SoftDevice and bootloader update on port {}", dfu_port);
+
+        return Ok(None);
+    }
+
+    let drive_path = get_uf2_drive(upload_port, progress).await?;
+
+    // Taken before copying, since the new bootloader can be back before the unmount is seen.
+    // The device's own drive goes away first, so its path counts as new when it returns.
+    let existing_drive_paths: HashSet<PathBuf> = list_uf2_drive_paths()?
+        .into_iter()
+        .filter(|existing_drive_path| *existing_drive_path != drive_path)
+        .collect();
+
+    progress.start_stage(JobStage::Write {
+        image_index: 1,
+        image_count: 1,
+        image_name: "bootloader".to_string(),
+    });
+
+    match tokio::fs::copy(&package_file_path, drive_path.join(&package_file_name)).await {
+        Ok(_) => (),
+        Err(e) => {
+            log::error!(
+                "Error while copying bootloader package to {}: {}",
+                drive_path.display(),
+                e
+            );
+
+            return Err(format!(
+                "Error while copying bootloader package to {}: {}",
+                drive_path.display(),
+                e
+            ));
+        }
+    };
+
+    progress.start_stage(JobStage::Reboot);
+
+    // The update app rewrites the bootloader, erases itself and drops back into the new
+    // bootloader, which mounts the drive again
+    if !wait_for_uf2_drive_unmount(&drive_path, progress).await? {
+        log::error!(
+            "Bootloader at {} did not run the update",
+            drive_path.display()
+        );
+
+        return Err(format!(
+            "The bootloader at {} did not run the update package. Check that the package matches the board.",
+            drive_path.display()
+        ));
+    }
+
+    let drive_path = match wait_for_new_uf2_drive(&existing_drive_paths, progress).await? {
+        Some(drive_path) => drive_path,
+        None => {
+            log::error!("No UF2 drive was mounted after the bootloader update");
+
+            return Err("The update was sent, but the bootloader's drive did not come back. Double-tap the reset button and check the bootloader version in INFO_UF2.TXT.".to_string());
+        }
+    };
+
+    let info = read_uf2_bootloader_info(&drive_path)?;
+
+    log::info!("Bootloader updated: {:?}", info);
+
+    Ok(Some(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{build_board, build_boards};
+
+    fn build_info(
+        bootloader_version: Option<&str>,
+        soft_device: Option<&str>,
+    ) -> Uf2BootloaderInfo {
+        Uf2BootloaderInfo {
+            bootloader_version: bootloader_version.map(|version| version.to_string()),
+            soft_device: soft_device.map(|soft_device| soft_device.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn get_required_update(
+        bootloader_version: Option<&str>,
+        soft_device: Option<&str>,
+    ) -> NrfBootloaderUpdate {
+        get_nrf_bootloader_status(&build_info(bootloader_version, soft_device)).required_update
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!(parse_version("0.6.1"), Some((0, 6, 1)));
+        assert_eq!(parse_version(" 6.1 "), Some((6, 1, 0)));
+        assert_eq!(parse_version("7"), Some((7, 0, 0)));
+        assert_eq!(parse_version("0.6.x"), None);
+        assert_eq!(parse_version(""), None);
+    }
+
+    #[test]
+    fn parses_soft_devices() {
+        assert_eq!(
+            parse_soft_device("S140 version 6.1.1"),
+            Some(("S140".to_string(), (6, 1, 1)))
+        );
+        assert_eq!(
+            parse_soft_device("S140 7.3.0"),
+            Some(("S140".to_string(), (7, 3, 0)))
+        );
+        assert_eq!(parse_soft_device("not found"), None);
+        assert_eq!(parse_soft_device("S140"), None);
+    }
+
+    #[test]
+    fn accepts_current_bootloader() {
+        assert_eq!(
+            get_required_update(Some("0.6.1"), Some("S140 version 6.1.1")),
+            NrfBootloaderUpdate::None
+        );
+        assert_eq!(
+            get_required_update(Some("0.9.2"), Some("s140 version 6.1.1")),
+            NrfBootloaderUpdate::None
+        );
+    }
+
+    #[test]
+    fn requires_bootloader_update_for_old_bootloader() {
+        assert_eq!(
+            get_required_update(Some("0.3.2"), Some("S140 version 6.1.1")),
+            NrfBootloaderUpdate::Bootloader
+        );
+        assert_eq!(
+            get_required_update(None, Some("S140 version 6.1.1")),
+            NrfBootloaderUpdate::Bootloader
+        );
+    }
+
+    #[test]
+    fn requires_soft_device_update_for_other_soft_devices() {
+        assert_eq!(
+            get_required_update(Some("0.6.1"), Some("S140 version 6.0.0")),
+            NrfBootloaderUpdate::SoftDeviceAndBootloader
+        );
+        // A newer major version changes the API the firmware links against
+        assert_eq!(
+            get_required_update(Some("0.6.1"), Some("S140 version 7.3.0")),
+            NrfBootloaderUpdate::SoftDeviceAndBootloader
+        );
+        assert_eq!(
+            get_required_update(Some("0.6.1"), Some("S132 version 6.1.1")),
+            NrfBootloaderUpdate::SoftDeviceAndBootloader
+        );
+        assert_eq!(
+            get_required_update(Some("0.6.1"), None),
+            NrfBootloaderUpdate::SoftDeviceAndBootloader
+        );
+    }
+
+    #[test]
+    fn reports_requirements() {
+        let status = get_nrf_bootloader_status(&build_info(Some("0.3.2"), Some("S140 6.1.1")));
+
+        assert_eq!(status.required_bootloader_version, "0.6.1");
+        assert_eq!(status.required_soft_device, "S140 6.1.1");
+    }
+
+    #[test]
+    fn blocks_incompatible_bootloaders() {
+        let compatible = get_nrf_bootloader_status(&build_info(Some("0.6.1"), Some("S140 6.1.1")));
+        let incompatible =
+            get_nrf_bootloader_status(&build_info(Some("0.3.2"), Some("S140 6.1.1")));
+
+        assert!(check_nrf_bootloader_compatible(&compatible).is_ok());
+        assert!(check_nrf_bootloader_compatible(&incompatible).is_err());
+    }
+
+    #[test]
+    fn picks_packages_by_platformio_target() {
+        let boards = build_boards();
+        let rak4631 = &boards[0];
+
+        assert_eq!(
+            get_nrf_bootloader_package_file_name(rak4631, &NrfBootloaderUpdate::Bootloader),
+            Ok("update-rak4631_bootloader-0.9.2_nosd.uf2".to_string())
+        );
+        assert_eq!(
+            get_nrf_bootloader_package_file_name(
+                rak4631,
+                &NrfBootloaderUpdate::SoftDeviceAndBootloader
+            ),
+            Ok("rak4631_bootloader-0.9.2_s140_6.1.1.zip".to_string())
+        );
+
+        let feather = build_board(255, "PRIVATE_HW", "feather_diy", "nrf52840");
+
+        assert_eq!(
+            get_nrf_bootloader_package_file_name(&feather, &NrfBootloaderUpdate::Bootloader),
+            Ok("update-feather_nrf52840_express_bootloader-0.9.2_nosd.uf2".to_string())
+        );
+    }
+
+    #[test]
+    fn refuses_boards_without_packages() {
+        let boards = build_boards();
+        let t_echo = &boards[1];
+
+        assert!(
+            get_nrf_bootloader_package_file_name(t_echo, &NrfBootloaderUpdate::Bootloader).is_err()
+        );
+        assert!(
+            get_nrf_bootloader_package_file_name(&boards[0], &NrfBootloaderUpdate::None).is_err()
+        );
+    }
+}
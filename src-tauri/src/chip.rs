@@ -0,0 +1,230 @@
+use espflash::{flasher::Flasher, targets::Chip};
+
+use crate::api::boards::Board;
+use crate::security::{read_esp_security_info, EspSecurityInfo};
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EspChipInfo {
+    pub chip: String,
+    pub revision: Option<String>,
+    pub flash_size: String,
+    pub flash_size_bytes: u32,
+    pub crystal_frequency_mhz: u32,
+    pub features: Vec<String>,
+    pub mac_address: String,
+    pub security: EspSecurityInfo,
+}
+
+// ESP-IDF app image header: magic byte, then the chip ID at a fixed offset
+pub(crate) const ESP_IMAGE_MAGIC: u8 = 0xE9;
+pub(crate) const ESP_IMAGE_CHIP_ID_OFFSET: usize = 12;
+
+/// Layout details that differ between ESP32 variants
+#[derive(Clone, Debug)]
+pub struct EspTarget {
+    pub chip: Chip,
+    /// Offset of the second stage bootloader, 0x1000 on Xtensa ESP32 / S2 and 0x0 on the rest
+    pub bootloader_offset: u32,
+    /// Chip ID stamped into the header of images built for this chip
+    pub image_chip_id: u16,
+    /// BLE OTA companion binary written to the OTA partition, if one is published for the chip
+    pub ble_ota_binary_name: Option<&'static str>,
+}
+
+/// Maps a board architecture from the Meshtastic API onto the chip espflash should detect
+pub fn get_esp_target(architecture: &String) -> Result<EspTarget, String> {
+    let (chip, bootloader_offset, image_chip_id, ble_ota_binary_name) =
+        match architecture.to_lowercase().as_str() {
+            "esp32" => (Chip::Esp32, 0x1000, 0x0000, Some("bleota.bin")),
+            "esp32-s2" => (Chip::Esp32s2, 0x1000, 0x0002, None), // No Bluetooth
+            "esp32-s3" => (Chip::Esp32s3, 0x0000, 0x0009, Some("bleota-s3.bin")),
+            "esp32-c3" => (Chip::Esp32c3, 0x0000, 0x0005, Some("bleota-c3.bin")),
+            "esp32-c6" => (Chip::Esp32c6, 0x0000, 0x000D, None),
+            "esp32-h2" => (Chip::Esp32h2, 0x0000, 0x0010, None),
+            _ => {
+                log::error!("Unsupported ESP architecture: {}", architecture);
+                return Err(format!("Unsupported ESP architecture: {}", architecture));
+            }
+        };
+
+    Ok(EspTarget {
+        chip,
+        bootloader_offset,
+        image_chip_id,
+        ble_ota_binary_name,
+    })
+}
+
+/// Checks that an image holds an app or bootloader header built for the target chip at `offset`
+pub fn check_esp_image(
+    image: &[u8],
+    offset: usize,
+    image_name: &str,
+    esp_target: &EspTarget,
+) -> Result<(), String> {
+    let header = match image.get(offset..offset + ESP_IMAGE_CHIP_ID_OFFSET + 2) {
+        Some(header) if header[0] == ESP_IMAGE_MAGIC => header,
+        _ => {
+            log::error!(
+                "{} has no ESP image header at 0x{:x}, expected for {}",
+                image_name,
+                offset,
+                esp_target.chip
+            );
+
+            return Err(format!(
+                "{} has no ESP image header at 0x{:x}, expected for {}",
+                image_name, offset, esp_target.chip
+            ));
+        }
+    };
+
+    let image_chip_id = u16::from_le_bytes([
+        header[ESP_IMAGE_CHIP_ID_OFFSET],
+        header[ESP_IMAGE_CHIP_ID_OFFSET + 1],
+    ]);
+
+    if image_chip_id != esp_target.image_chip_id {
+        log::error!(
+            "{} is built for chip ID 0x{:04x} but {} expects chip ID 0x{:04x}",
+            image_name,
+            image_chip_id,
+            esp_target.chip,
+            esp_target.image_chip_id
+        );
+
+        return Err(format!(
+            "{} is built for chip ID 0x{:04x} but {} expects chip ID 0x{:04x}",
+            image_name, image_chip_id, esp_target.chip, esp_target.image_chip_id
+        ));
+    }
+
+    Ok(())
+}
+
+/// Reads the connected chip's details and aborts if they don't match the selected board
+pub fn detect_esp_chip(
+    flasher: &mut Flasher,
+    board: &Board,
+    required_flash_size: u32,
+) -> Result<EspChipInfo, String> {
+    let device_info = match flasher.device_info() {
+        Ok(device_info) => device_info,
+        Err(e) => {
+            log::error!("Error while reading device info: {}", e);
+            return Err(format!("Error while reading device info: {}", e));
+        }
+    };
+
+    let expected_chip = get_esp_target(&board.architecture)?.chip;
+
+    if device_info.chip != expected_chip {
+        log::error!(
+            "Selected board {} expects chip {} but the connected device reports {}",
+            board.display_name,
+            expected_chip,
+            device_info.chip
+        );
+
+        return Err(format!(
+            "Selected board {} expects chip {} but the connected device reports {}. Check that the correct board and serial port are selected.",
+            board.display_name, expected_chip, device_info.chip
+        ));
+    }
+
+    // Only read once the chip is known, since the eFuse layout differs between chips
+    let security = read_esp_security_info(flasher, &device_info.chip)?;
+
+    let chip_info = EspChipInfo {
+        chip: device_info.chip.to_string(),
+        revision: device_info
+            .revision
+            .map(|(major, minor)| format!("{}.{}", major, minor)),
+        flash_size: device_info.flash_size.to_string(),
+        flash_size_bytes: device_info.flash_size.size(),
+        crystal_frequency_mhz: device_info.crystal_frequency,
+        features: device_info
+            .features
+            .iter()
+            .map(|feature| feature.to_string())
+            .collect(),
+        mac_address: device_info.mac_address.clone(),
+        security,
+    };
+
+    log::info!("Detected chip: {:?}", chip_info);
+
+    if chip_info.flash_size_bytes < required_flash_size {
+        log::error!(
+            "Firmware for {} requires {} bytes of flash but the connected device only has {}",
+            board.display_name,
+            required_flash_size,
+            chip_info.flash_size
+        );
+
+        return Err(format!(
+            "Firmware for {} requires {} bytes of flash but the connected device only has {}",
+            board.display_name, required_flash_size, chip_info.flash_size
+        ));
+    }
+
+    Ok(chip_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::build_esp_image;
+
+    #[test]
+    fn maps_architectures_to_targets() {
+        let esp32 = get_esp_target(&"esp32".to_string()).unwrap();
+        assert_eq!(esp32.chip, Chip::Esp32);
+        assert_eq!(esp32.bootloader_offset, 0x1000);
+
+        let esp32c3 = get_esp_target(&"ESP32-C3".to_string()).unwrap();
+        assert_eq!(esp32c3.chip, Chip::Esp32c3);
+        assert_eq!(esp32c3.bootloader_offset, 0x0);
+        assert_eq!(esp32c3.image_chip_id, 0x0005);
+
+        assert!(get_esp_target(&"nrf52840".to_string()).is_err());
+    }
+
+    #[test]
+    fn accepts_image_for_target_chip() {
+        let esp32 = get_esp_target(&"esp32".to_string()).unwrap();
+        let esp32s3 = get_esp_target(&"esp32-s3".to_string()).unwrap();
+
+        assert!(check_esp_image(
+            &build_esp_image(0x1000, 0x0000),
+            0x1000,
+            "Factory image",
+            &esp32
+        )
+        .is_ok());
+        assert!(check_esp_image(&build_esp_image(0, 0x0009), 0, "Update image", &esp32s3).is_ok());
+    }
+
+    #[test]
+    fn rejects_image_for_other_chip() {
+        let esp32c3 = get_esp_target(&"esp32-c3".to_string()).unwrap();
+        let esp32c6_image = build_esp_image(0, 0x000D);
+
+        let error = check_esp_image(&esp32c6_image, 0, "Update image", &esp32c3).unwrap_err();
+
+        assert!(error.contains("0x000d"));
+    }
+
+    #[test]
+    fn rejects_missing_header() {
+        let esp32 = get_esp_target(&"esp32".to_string()).unwrap();
+
+        // Header at 0x0, where S3 and later chips put the bootloader, rather than ESP32's 0x1000
+        assert!(
+            check_esp_image(&build_esp_image(0, 0x0000), 0x1000, "Factory image", &esp32).is_err()
+        );
+        assert!(check_esp_image(&[ESP_IMAGE_MAGIC; 8], 0, "Update image", &esp32).is_err());
+        assert!(check_esp_image(&[], 0, "Update image", &esp32).is_err());
+    }
+}
@@ -1,13 +1,266 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
 use serialport::SerialPortInfo;
+use tauri::Manager;
+use tauri_plugin_store::{with_store, StoreCollection};
 
-use crate::api::boards::Board;
-use crate::api::firmware::FirmwareRelease;
-use crate::flasher::{self, parse_firmware_version};
-use crate::fs::{
+use meshtastic_desktop_flasher::api::{self, boards::Board, firmware::FirmwareRelease};
+use meshtastic_desktop_flasher::backup::{
+    backup_esp32, list_backups, restore_esp32_backup, restore_uf2_backup, BackupMetadata,
+};
+use meshtastic_desktop_flasher::bootloader::{
+    read_nrf_bootloader_status, update_nrf_bootloader as install_nrf_bootloader_update,
+    NrfBootloaderStatus, NrfBootloaderUpdate,
+};
+use meshtastic_desktop_flasher::chip::{get_esp_target, EspChipInfo};
+use meshtastic_desktop_flasher::erase::{erase_esp32, EraseTarget};
+use meshtastic_desktop_flasher::flasher::{
+    self, parse_firmware_version, EspConnectionConfig, EspResetStrategy, FlashMode, NrfUploadMethod,
+};
+use meshtastic_desktop_flasher::fs::{
     create_archive_from_bytes, extract_binary_from_archive, get_firmware_file_name,
-    get_temp_file_path, write_binary_to_temp_file,
+    get_nrf_dfu_package_file_name, get_resource_file_path, get_uf2_erase_file_name,
+    get_update_firmware_file_name, write_binary_to_temp_file,
+};
+use meshtastic_desktop_flasher::identity::{read_esp32_identity, EspDeviceIdentity};
+use meshtastic_desktop_flasher::progress::{
+    JobProgress, JobProgressUpdate, JobStage, NoopProgressSink, ProgressSink,
 };
-use crate::{api, state};
+use meshtastic_desktop_flasher::rp2040::{plan_rp2040_flash, Rp2040FlashPlan};
+use meshtastic_desktop_flasher::uf2::{list_uf2_drives, Uf2BootloaderInfo, Uf2Drive};
+
+use crate::paths::{
+    create_or_locate_backup_directory, get_temp_directory, get_temp_file_path,
+    locate_bootloader_resource_directory, locate_erase_resource_directory,
+};
+use crate::state;
+
+const BAUD_RATE_STORE_PATH: &str = ".baud_rates.dat";
+
+async fn get_board_by_hw_model(
+    boards_state: &tauri::State<'_, state::BoardsState>,
+    hw_model: u32,
+) -> Result<Board, String> {
+    // Use and unlock boards mutex
+    let boards_guard = boards_state.inner.lock().await;
+
+    match boards_guard.iter().find(|b| b.hw_model == hw_model) {
+        Some(board) => Ok(board.clone()),
+        None => {
+            log::error!("Board with hardware model {} not found", hw_model);
+            Err(format!("Board with hardware model {} not found", hw_model))
+        }
+    }
+}
+
+async fn get_firmware_release(
+    firmware_releases_state: &tauri::State<'_, state::FirmwareReleasesState>,
+    firmware_version_id: &String,
+) -> Result<FirmwareRelease, String> {
+    // Use and unlock releases mutex
+    let firmware_releases_guard = firmware_releases_state.inner.lock().await;
+
+    let stable_firmware_release = firmware_releases_guard
+        .releases
+        .stable
+        .iter()
+        .find(|r| r.id == *firmware_version_id);
+
+    let alpha_firmware_release = firmware_releases_guard
+        .releases
+        .alpha
+        .iter()
+        .find(|r| r.id == *firmware_version_id);
+
+    match (stable_firmware_release, alpha_firmware_release) {
+        (Some(stable), _) => Ok(stable.clone()),
+        (_, Some(alpha)) => Ok(alpha.clone()),
+        (None, None) => {
+            log::error!(
+                "Firmware release {} not found in stable or alpha channels",
+                firmware_version_id
+            );
+
+            Err(format!(
+                "Firmware release {} not found in stable or alpha channels",
+                firmware_version_id
+            ))
+        }
+    }
+}
+
+fn get_baud_rate_key(upload_port: &String, board: &Board) -> String {
+    format!("{}:{}", upload_port, board.hw_model_slug)
+}
+
+/// Last baud rate that worked for this port and board, loaded from the store on first use
+async fn get_remembered_baud_rate(
+    app_handle: &tauri::AppHandle,
+    baud_rate_state: &tauri::State<'_, state::BaudRateState>,
+    baud_rate_key: &String,
+) -> Option<u32> {
+    let mut baud_rate_guard = baud_rate_state.inner.lock().await;
+
+    if let Some(baud_rate) = baud_rate_guard.get(baud_rate_key) {
+        return Some(*baud_rate);
+    }
+
+    let stores = app_handle.state::<StoreCollection<tauri::Wry>>();
+
+    match with_store(app_handle.clone(), stores, BAUD_RATE_STORE_PATH, |store| {
+        Ok(store
+            .get(baud_rate_key)
+            .and_then(|baud_rate| baud_rate.as_u64())
+            .and_then(|baud_rate| u32::try_from(baud_rate).ok()))
+    }) {
+        Ok(Some(baud_rate)) => {
+            baud_rate_guard.insert(baud_rate_key.clone(), baud_rate);
+            Some(baud_rate)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            log::warn!("Error while reading remembered baud rates: {}", e);
+            None
+        }
+    }
+}
+
+/// Remembers a baud rate that worked, in memory and in the store so it survives restarts
+async fn remember_baud_rate(
+    app_handle: &tauri::AppHandle,
+    baud_rate_state: &tauri::State<'_, state::BaudRateState>,
+    baud_rate_key: String,
+    baud_rate: u32,
+) {
+    let mut baud_rate_guard = baud_rate_state.inner.lock().await;
+    baud_rate_guard.insert(baud_rate_key.clone(), baud_rate);
+
+    let stores = app_handle.state::<StoreCollection<tauri::Wry>>();
+
+    // The flash already succeeded, so failing to persist only costs a slower default next time
+    if let Err(e) = with_store(app_handle.clone(), stores, BAUD_RATE_STORE_PATH, |store| {
+        store.insert(baud_rate_key, serde_json::json!(baud_rate))?;
+        store.save()
+    }) {
+        log::warn!("Error while saving remembered baud rate: {}", e);
+    }
+}
+
+/// Uses the requested baud rate, then the last one that worked for this port and board
+async fn get_esp_connection_config(
+    app_handle: &tauri::AppHandle,
+    baud_rate_state: &tauri::State<'_, state::BaudRateState>,
+    upload_port: &String,
+    board: &Board,
+    baud_rate: Option<u32>,
+    reset_strategy: Option<EspResetStrategy>,
+) -> Result<EspConnectionConfig, String> {
+    let connection_config = match baud_rate {
+        Some(baud_rate) => EspConnectionConfig::with_baud_rate(baud_rate)?,
+        None => {
+            let baud_rate_key = get_baud_rate_key(upload_port, board);
+
+            match get_remembered_baud_rate(app_handle, baud_rate_state, &baud_rate_key).await {
+                Some(baud_rate) => {
+                    log::info!(
+                        "Using remembered baud rate {} for {}",
+                        baud_rate,
+                        upload_port
+                    );
+                    EspConnectionConfig::with_baud_rate(baud_rate)?
+                }
+                None => EspConnectionConfig::default(),
+            }
+        }
+    };
+
+    Ok(EspConnectionConfig {
+        reset_strategy,
+        ..connection_config
+    })
+}
+
+/// Emits `flash-job-progress-{port}` events to every Tauri window
+pub struct TauriProgressSink {
+    app_handle: tauri::AppHandle,
+}
+
+impl TauriProgressSink {
+    pub fn new(app_handle: tauri::AppHandle) -> Self {
+        TauriProgressSink { app_handle }
+    }
+}
+
+impl ProgressSink for TauriProgressSink {
+    fn emit(&self, update: JobProgressUpdate) {
+        match self.app_handle.emit_all(
+            format!("flash-job-progress-{}", update.board_id.0).as_str(),
+            update,
+        ) {
+            Ok(_) => (),
+            Err(e) => {
+                log::error!("Error while emitting flash job progress: {}", e);
+            }
+        };
+    }
+}
+
+/// Progress for a job started from the UI, reported as Tauri events
+fn get_job_progress(app_handle: &tauri::AppHandle, upload_port: &String) -> JobProgress {
+    JobProgress::new(
+        Arc::new(TauriProgressSink::new(app_handle.clone())),
+        upload_port,
+    )
+}
+
+fn check_esp32_board(board: &Board) -> Result<(), String> {
+    if !board.architecture.contains("esp") {
+        log::error!(
+            "Operation is only supported on ESP32 boards, got architecture {}",
+            board.architecture
+        );
+
+        return Err(format!(
+            "Operation is only supported on ESP32 boards, got architecture {}",
+            board.architecture
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_rp2040_board(board: &Board) -> Result<(), String> {
+    if !board.architecture.contains("rp2040") {
+        log::error!(
+            "Operation is only supported on RP2040 boards, got architecture {}",
+            board.architecture
+        );
+
+        return Err(format!(
+            "Operation is only supported on RP2040 boards, got architecture {}",
+            board.architecture
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_nrf_board(board: &Board) -> Result<(), String> {
+    if !board.architecture.contains("nrf") {
+        log::error!(
+            "Operation is only supported on nRF52 boards, got architecture {}",
+            board.architecture
+        );
+
+        return Err(format!(
+            "Operation is only supported on nRF52 boards, got architecture {}",
+            board.architecture
+        ));
+    }
+
+    Ok(())
+}
 
 #[tauri::command]
 pub async fn fetch_firmware_releases(
@@ -56,65 +309,214 @@ pub async fn get_available_serial_ports() -> Result<Vec<SerialPortInfo>, String>
     Ok(available_ports)
 }
 
+/// Arguments of a `flash_device` call, optional settings fall back to their defaults
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlashJobRequest {
+    pub hw_model: u32,
+    pub firmware_version_id: String,
+    pub upload_port: String,
+    pub flash_mode: Option<FlashMode>,
+    pub verify: Option<bool>,
+    pub backup: Option<bool>,
+    pub baud_rate: Option<u32>,
+    pub reset_strategy: Option<EspResetStrategy>,
+    pub write_retries: Option<u32>,
+    pub nrf_upload_method: Option<NrfUploadMethod>,
+    pub ignore_bootloader_check: Option<bool>,
+    pub ignore_binary_info_check: Option<bool>,
+    pub factory_erase: Option<bool>, // UF2 variants only, wipes the flash before copying
+}
+
+/// Claims a port for a job, so no two jobs ever drive the same device at once
+async fn register_port_job(
+    flash_jobs_state: &tauri::State<'_, state::FlashJobsState>,
+    upload_port: &String,
+    progress: &JobProgress,
+) -> Result<(), String> {
+    // Use and unlock flash jobs mutex
+    let mut flash_jobs_guard = flash_jobs_state.inner.lock().await;
+
+    if flash_jobs_guard.contains_key(upload_port) {
+        log::error!("A job is already running on port {}", upload_port);
+        return Err(format!("A job is already running on port {}", upload_port));
+    }
+
+    flash_jobs_guard.insert(upload_port.clone(), progress.cancel_flag());
+
+    Ok(())
+}
+
+/// Releases a port whether its job succeeded, failed or was cancelled
+async fn release_port_job(
+    flash_jobs_state: &tauri::State<'_, state::FlashJobsState>,
+    upload_port: &String,
+) {
+    // Use and unlock flash jobs mutex
+    let mut flash_jobs_guard = flash_jobs_state.inner.lock().await;
+    flash_jobs_guard.remove(upload_port);
+}
+
+#[tauri::command]
+pub async fn get_available_uf2_drives(
+    boards_state: tauri::State<'_, state::BoardsState>,
+) -> Result<Vec<Uf2Drive>, String> {
+    log::info!("Called \"get_available_uf2_drives\" command with no args");
+
+    // Use and unlock boards mutex
+    let boards_guard = boards_state.inner.lock().await;
+
+    list_uf2_drives(&boards_guard)
+}
+
+/// Arguments of a `get_rp2040_flash_plan` call
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rp2040FlashPlanRequest {
+    pub hw_model: u32,
+    pub firmware_version_id: String,
+}
+
+#[tauri::command]
+pub async fn get_rp2040_flash_plan(
+    firmware_releases_state: tauri::State<'_, state::FirmwareReleasesState>,
+    boards_state: tauri::State<'_, state::BoardsState>,
+    request: Rp2040FlashPlanRequest,
+) -> Result<Rp2040FlashPlan, String> {
+    log::info!(
+        "Called \"get_rp2040_flash_plan\" command with args: request: {:?}",
+        request
+    );
+
+    let board = get_board_by_hw_model(&boards_state, request.hw_model).await?;
+
+    check_rp2040_board(&board)?;
+
+    let firmware_release =
+        get_firmware_release(&firmware_releases_state, &request.firmware_version_id).await?;
+    let firmware_version = parse_firmware_version(&request.firmware_version_id)?;
+
+    // Planning doesn't claim a port, so nothing listens for its download progress
+    let mut progress = JobProgress::new(Arc::new(NoopProgressSink), &request.firmware_version_id);
+
+    let firmware_zip_bundle_bytes =
+        api::fetch_firmware_bundle(firmware_release.zip_url.clone(), &mut progress).await?;
+
+    let mut archive = create_archive_from_bytes(firmware_zip_bundle_bytes).await?;
+
+    let firmware_file_name = get_firmware_file_name(&board, &firmware_version)?;
+    let image = extract_binary_from_archive(&mut archive, &firmware_file_name).await?;
+
+    plan_rp2040_flash(firmware_file_name, &image, &board, &firmware_version)
+}
+
 #[tauri::command]
 pub async fn flash_device(
     app_handle: tauri::AppHandle,
     firmware_releases_state: tauri::State<'_, state::FirmwareReleasesState>,
     boards_state: tauri::State<'_, state::BoardsState>,
-    hw_model: u32,
-    firmware_version_id: String,
-    upload_port: String,
-) -> Result<(), String> {
-    log::info!("Called \"flash_device\" command with args: hw_model: {}, firmware_version_id: {}, upload_port: {}", hw_model, firmware_version_id, upload_port);
+    baud_rate_state: tauri::State<'_, state::BaudRateState>,
+    flash_jobs_state: tauri::State<'_, state::FlashJobsState>,
+    request: FlashJobRequest,
+) -> Result<flasher::FlashResult, String> {
+    log::info!(
+        "Called \"flash_device\" command with args: request: {:?}",
+        request
+    );
 
-    // Use and unlock boards mutex
-    let board: Board = {
-        let boards_guard = boards_state.inner.lock().await;
-
-        match boards_guard.iter().find(|b| b.hw_model == hw_model) {
-            Some(board) => board.clone(),
-            None => {
-                log::error!("Board with hardware model {} not found", hw_model);
-                return Err(format!("Board with hardware model {} not found", hw_model));
-            }
-        }
-    };
+    let upload_port = request.upload_port.clone();
+    let mut progress = get_job_progress(&app_handle, &upload_port);
+
+    register_port_job(&flash_jobs_state, &upload_port, &progress).await?;
+
+    let flash_result = run_flash_job(
+        app_handle,
+        firmware_releases_state,
+        boards_state,
+        baud_rate_state,
+        request,
+        &mut progress,
+    )
+    .await;
+
+    release_port_job(&flash_jobs_state, &upload_port).await;
+
+    flash_result
+}
+
+async fn run_flash_job(
+    app_handle: tauri::AppHandle,
+    firmware_releases_state: tauri::State<'_, state::FirmwareReleasesState>,
+    boards_state: tauri::State<'_, state::BoardsState>,
+    baud_rate_state: tauri::State<'_, state::BaudRateState>,
+    request: FlashJobRequest,
+    progress: &mut JobProgress,
+) -> Result<flasher::FlashResult, String> {
+    let FlashJobRequest {
+        hw_model,
+        firmware_version_id,
+        upload_port,
+        flash_mode,
+        verify,
+        backup,
+        baud_rate,
+        reset_strategy,
+        write_retries,
+        nrf_upload_method,
+        ignore_bootloader_check,
+        ignore_binary_info_check,
+        factory_erase,
+    } = request;
+
+    let flash_mode = flash_mode.unwrap_or_default(); // Keeps the device's config unless asked
+    let nrf_upload_method = nrf_upload_method.unwrap_or_default();
+    let verify = verify.unwrap_or(true); // Readback verification is opt-out
+    let factory_erase = factory_erase.unwrap_or(false);
+
+    let board = get_board_by_hw_model(&boards_state, hw_model).await?;
 
     log::info!("Using board: {:?}", board);
 
-    // Use and unlock releases mutex
-    let firmware_release: FirmwareRelease = {
-        let firmware_releases_guard = firmware_releases_state.inner.lock().await;
-
-        let stable_firmware_release = firmware_releases_guard
-            .releases
-            .stable
-            .iter()
-            .find(|r| r.id == firmware_version_id);
-
-        let alpha_firmware_release = firmware_releases_guard
-            .releases
-            .alpha
-            .iter()
-            .find(|r| r.id == firmware_version_id);
-
-        match (stable_firmware_release, alpha_firmware_release) {
-            (Some(stable), _) => stable.clone(),
-            (_, Some(alpha)) => alpha.clone(),
-            (None, None) => {
-                log::error!(
-                    "Firmware release {} not found in stable or alpha channels",
-                    firmware_version_id
-                );
-
-                return Err(format!(
-                    "Firmware release {} not found in stable or alpha channels",
-                    firmware_version_id
-                ));
-            }
+    // Serial DFU never mounts the bootloader drive the erase image is copied to
+    if factory_erase
+        && board.architecture.contains("nrf")
+        && matches!(nrf_upload_method, NrfUploadMethod::SerialDfu)
+    {
+        log::error!("Factory erase is only supported when copying UF2 images");
+        return Err("Factory erase is only supported when copying UF2 images".to_string());
+    }
+
+    // Serial loader settings only apply to ESP32 variants
+    let connection_config = if board.architecture.contains("esp") {
+        let mut connection_config = get_esp_connection_config(
+            &app_handle,
+            &baud_rate_state,
+            &upload_port,
+            &board,
+            baud_rate,
+            reset_strategy,
+        )
+        .await?;
+
+        if let Some(write_retries) = write_retries {
+            connection_config.max_write_retries = write_retries;
         }
+
+        connection_config
+    } else {
+        EspConnectionConfig::default()
+    };
+
+    // Only relevant to ESP32 variants, resolved up front so unsupported chips fail early
+    let esp_target = if board.architecture.contains("esp") {
+        Some(get_esp_target(&board.architecture)?)
+    } else {
+        None
     };
 
+    let firmware_release =
+        get_firmware_release(&firmware_releases_state, &firmware_version_id).await?;
+
     log::info!("Using firmware release: {:?}", firmware_release);
 
     // Process information from mutexes
@@ -124,18 +526,21 @@ pub async fn flash_device(
 
     log::info!("Using firmware version: {:?}", parsed_firmware_version);
 
-    let firmware_zip_bundle_bytes = api::fetch_firmware_bundle(firmware_zip_url.clone()).await?;
+    let firmware_zip_bundle_bytes =
+        api::fetch_firmware_bundle(firmware_zip_url.clone(), progress).await?;
 
     // Write firmware file to disk
 
     let firmware_file_name = get_firmware_file_name(&board, &parsed_firmware_version)?;
 
     // Only relevant to ESP32 variants
-    let ble_ota_binary_name: String = if board.architecture.contains("esp32-s3") {
-        "bleota-s3.bin".to_string()
-    } else {
-        "bleota.bin".to_string()
-    };
+    let update_binary_name = get_update_firmware_file_name(&board, &parsed_firmware_version);
+
+    // Only relevant to ESP32 variants with a BLE OTA binary
+    let ble_ota_binary_name: Option<String> = esp_target
+        .as_ref()
+        .and_then(|esp_target| esp_target.ble_ota_binary_name)
+        .map(|ble_ota_binary_name| ble_ota_binary_name.to_string());
 
     // Only relevant to ESP32 variants
     let littlefs_binary_name: String = format!(
@@ -147,42 +552,514 @@ pub async fn flash_device(
     );
 
     let temp_firmware_file_path = get_temp_file_path(&app_handle, firmware_file_name.clone())?;
-    let temp_ble_ota_file_path = get_temp_file_path(&app_handle, ble_ota_binary_name.clone())?;
+    let temp_update_file_path = get_temp_file_path(&app_handle, update_binary_name.clone())?;
+    let temp_ble_ota_file_path = match &ble_ota_binary_name {
+        Some(ble_ota_binary_name) => Some(get_temp_file_path(
+            &app_handle,
+            ble_ota_binary_name.clone(),
+        )?),
+        None => None,
+    };
     let temp_littlefs_file_path = get_temp_file_path(&app_handle, littlefs_binary_name.clone())?;
 
+    // Only relevant to nRF52 serial DFU
+    let dfu_package_file_name = get_nrf_dfu_package_file_name(&board, &parsed_firmware_version);
+    let temp_dfu_package_file_path =
+        get_temp_file_path(&app_handle, dfu_package_file_name.clone())?;
+
+    progress.start_stage(JobStage::VerifyDownload);
+
     let mut archive = create_archive_from_bytes(firmware_zip_bundle_bytes).await?;
 
+    progress.start_stage(JobStage::Extract);
+
     let firmware_binary_contents =
         extract_binary_from_archive(&mut archive, &firmware_file_name).await?;
 
-    // Only relevant to ESP32 variants
-    let ble_ota_binary_contents =
-        extract_binary_from_archive(&mut archive, &ble_ota_binary_name).await?;
-
     // Only relevant to ESP32 variants
     let littlefs_binary_contents =
         extract_binary_from_archive(&mut archive, &littlefs_binary_name).await?;
 
+    // Only relevant to ESP32 update installs
+    if board.architecture.contains("esp") {
+        if let FlashMode::Update = flash_mode {
+            let update_binary_contents =
+                extract_binary_from_archive(&mut archive, &update_binary_name).await?;
+
+            write_binary_to_temp_file(temp_update_file_path.clone(), update_binary_contents)
+                .await?;
+        }
+    }
+
     // Write files to temp directory
 
     write_binary_to_temp_file(temp_firmware_file_path.clone(), firmware_binary_contents).await?;
-    write_binary_to_temp_file(temp_ble_ota_file_path.clone(), ble_ota_binary_contents).await?;
     write_binary_to_temp_file(temp_littlefs_file_path.clone(), littlefs_binary_contents).await?;
 
+    // Only relevant to ESP32 variants with a BLE OTA binary
+    if let (Some(ble_ota_binary_name), Some(temp_ble_ota_file_path)) =
+        (&ble_ota_binary_name, &temp_ble_ota_file_path)
+    {
+        let ble_ota_binary_contents =
+            extract_binary_from_archive(&mut archive, ble_ota_binary_name).await?;
+
+        write_binary_to_temp_file(temp_ble_ota_file_path.clone(), ble_ota_binary_contents).await?;
+    }
+
+    // Only relevant to nRF52 serial DFU
+    if board.architecture.contains("nrf") {
+        if let NrfUploadMethod::SerialDfu = nrf_upload_method {
+            let dfu_package_contents =
+                extract_binary_from_archive(&mut archive, &dfu_package_file_name).await?;
+
+            write_binary_to_temp_file(temp_dfu_package_file_path.clone(), dfu_package_contents)
+                .await?;
+        }
+    }
+
+    // Only relevant to UF2 variants, taken from the release bundle when it has one
+    let temp_uf2_erase_file_path = if factory_erase && !board.architecture.contains("esp") {
+        let erase_file_name = get_uf2_erase_file_name(&board)?;
+
+        if archive.file_names().any(|name| name == erase_file_name) {
+            let erase_file_contents =
+                extract_binary_from_archive(&mut archive, &erase_file_name).await?;
+            let temp_erase_file_path = get_temp_file_path(&app_handle, erase_file_name)?;
+
+            write_binary_to_temp_file(temp_erase_file_path.clone(), erase_file_contents).await?;
+
+            Some(temp_erase_file_path)
+        } else {
+            Some(get_resource_file_path(
+                &locate_erase_resource_directory(&app_handle)?,
+                &erase_file_name,
+            )?)
+        }
+    } else {
+        None
+    };
+
     // Flash board
 
-    flasher::flash_board(
-        app_handle,
-        temp_firmware_file_path,
-        temp_ble_ota_file_path,
-        temp_littlefs_file_path,
+    progress.check_cancelled()?;
+
+    let baud_rate_key = get_baud_rate_key(&upload_port, &board);
+
+    let flash_files = flasher::FlashFiles {
+        temp_directory: get_temp_directory(&app_handle)?,
         firmware_file_name,
+        firmware_file_path: temp_firmware_file_path,
+        update_file_path: temp_update_file_path,
+        ble_ota_file_path: temp_ble_ota_file_path,
+        littlefs_file_path: temp_littlefs_file_path,
+        dfu_package_file_path: temp_dfu_package_file_path,
+        uf2_erase_file_path: temp_uf2_erase_file_path,
+        firmware_version: parsed_firmware_version,
+    };
+
+    let flash_result = flasher::flash_board(
+        flash_files,
         upload_port,
         board,
+        flasher::FlashOptions {
+            flash_mode,
+            verify,
+            backup_directory: if backup.unwrap_or(false) {
+                Some(create_or_locate_backup_directory(&app_handle).await?)
+            } else {
+                None
+            },
+            connection_config,
+            nrf_upload_method,
+            ignore_bootloader_check: ignore_bootloader_check.unwrap_or(false),
+            ignore_binary_info_check: ignore_binary_info_check.unwrap_or(false),
+        },
+        progress,
     )
     .await?;
 
-    Ok(())
+    progress.complete();
+
+    if let Some(baud_rate) = flash_result.baud_rate {
+        remember_baud_rate(&app_handle, &baud_rate_state, baud_rate_key, baud_rate).await;
+    }
+
+    log::info!("Flash result: {:?}", flash_result);
+
+    Ok(flash_result)
+}
+
+#[tauri::command]
+pub async fn cancel_flash_job(
+    flash_jobs_state: tauri::State<'_, state::FlashJobsState>,
+    upload_port: String,
+) -> Result<(), String> {
+    log::info!(
+        "Called \"cancel_flash_job\" command with args: upload_port: {}",
+        upload_port
+    );
+
+    // Use and unlock flash jobs mutex
+    let flash_jobs_guard = flash_jobs_state.inner.lock().await;
+
+    match flash_jobs_guard.get(&upload_port) {
+        Some(cancel_flag) => {
+            // The job stops at its next safe abort point
+            cancel_flag.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+        None => {
+            log::error!("No job is running on port {}", upload_port);
+            Err(format!("No job is running on port {}", upload_port))
+        }
+    }
+}
+
+/// Arguments shared by the commands that operate on a connected ESP32 outside a flash job
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EspDeviceRequest {
+    pub hw_model: u32,
+    pub upload_port: String,
+    pub baud_rate: Option<u32>,
+    pub reset_strategy: Option<EspResetStrategy>,
+}
+
+/// Resolves the ESP32 board and connection settings of a device request
+async fn get_esp_device(
+    app_handle: &tauri::AppHandle,
+    boards_state: &tauri::State<'_, state::BoardsState>,
+    baud_rate_state: &tauri::State<'_, state::BaudRateState>,
+    request: &EspDeviceRequest,
+) -> Result<(Board, EspConnectionConfig), String> {
+    let board = get_board_by_hw_model(boards_state, request.hw_model).await?;
+    check_esp32_board(&board)?;
+
+    let connection_config = get_esp_connection_config(
+        app_handle,
+        baud_rate_state,
+        &request.upload_port,
+        &board,
+        request.baud_rate,
+        request.reset_strategy,
+    )
+    .await?;
+
+    Ok((board, connection_config))
+}
+
+#[tauri::command]
+pub async fn erase_device(
+    app_handle: tauri::AppHandle,
+    boards_state: tauri::State<'_, state::BoardsState>,
+    baud_rate_state: tauri::State<'_, state::BaudRateState>,
+    flash_jobs_state: tauri::State<'_, state::FlashJobsState>,
+    request: EspDeviceRequest,
+    erase_target: EraseTarget,
+) -> Result<EspChipInfo, String> {
+    log::info!(
+        "Called \"erase_device\" command with args: request: {:?}, erase_target: {:?}",
+        request,
+        erase_target
+    );
+
+    let (board, connection_config) =
+        get_esp_device(&app_handle, &boards_state, &baud_rate_state, &request).await?;
+
+    let temp_directory = get_temp_directory(&app_handle)?;
+    let upload_port = request.upload_port;
+    let mut progress = get_job_progress(&app_handle, &upload_port);
+
+    register_port_job(&flash_jobs_state, &upload_port, &progress).await?;
+
+    let erase_result = erase_esp32(
+        temp_directory,
+        upload_port.clone(),
+        &board,
+        erase_target,
+        0,
+        &connection_config,
+        &mut progress,
+    )
+    .await;
+
+    release_port_job(&flash_jobs_state, &upload_port).await;
+
+    let chip_info = erase_result?;
+
+    progress.complete();
+
+    Ok(chip_info)
+}
+
+#[tauri::command]
+pub async fn backup_device(
+    app_handle: tauri::AppHandle,
+    boards_state: tauri::State<'_, state::BoardsState>,
+    baud_rate_state: tauri::State<'_, state::BaudRateState>,
+    flash_jobs_state: tauri::State<'_, state::FlashJobsState>,
+    request: EspDeviceRequest,
+) -> Result<BackupMetadata, String> {
+    log::info!(
+        "Called \"backup_device\" command with args: request: {:?}",
+        request
+    );
+
+    let (board, connection_config) =
+        get_esp_device(&app_handle, &boards_state, &baud_rate_state, &request).await?;
+
+    let backup_directory = create_or_locate_backup_directory(&app_handle).await?;
+    let temp_directory = get_temp_directory(&app_handle)?;
+    let upload_port = request.upload_port;
+    let mut progress = get_job_progress(&app_handle, &upload_port);
+
+    register_port_job(&flash_jobs_state, &upload_port, &progress).await?;
+
+    let backup_result = backup_esp32(
+        &backup_directory,
+        temp_directory,
+        upload_port.clone(),
+        &board,
+        &connection_config,
+        &mut progress,
+    )
+    .await;
+
+    release_port_job(&flash_jobs_state, &upload_port).await;
+
+    let backup_metadata = backup_result?;
+
+    progress.complete();
+
+    Ok(backup_metadata)
+}
+
+#[tauri::command]
+pub async fn get_device_backups(
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<BackupMetadata>, String> {
+    log::info!("Called \"get_device_backups\" command with no args");
+
+    let backup_directory = create_or_locate_backup_directory(&app_handle).await?;
+
+    list_backups(&backup_directory).await
+}
+
+#[tauri::command]
+pub async fn restore_device(
+    app_handle: tauri::AppHandle,
+    boards_state: tauri::State<'_, state::BoardsState>,
+    baud_rate_state: tauri::State<'_, state::BaudRateState>,
+    flash_jobs_state: tauri::State<'_, state::FlashJobsState>,
+    request: EspDeviceRequest,
+    backup_file_name: String,
+) -> Result<EspChipInfo, String> {
+    log::info!(
+        "Called \"restore_device\" command with args: request: {:?}, backup_file_name: {}",
+        request,
+        backup_file_name
+    );
+
+    let (board, connection_config) =
+        get_esp_device(&app_handle, &boards_state, &baud_rate_state, &request).await?;
+
+    let backup_directory = create_or_locate_backup_directory(&app_handle).await?;
+    let temp_directory = get_temp_directory(&app_handle)?;
+    let upload_port = request.upload_port;
+    let mut progress = get_job_progress(&app_handle, &upload_port);
+
+    register_port_job(&flash_jobs_state, &upload_port, &progress).await?;
+
+    let restore_result = restore_esp32_backup(
+        &backup_directory,
+        temp_directory,
+        upload_port.clone(),
+        &board,
+        backup_file_name,
+        &connection_config,
+        &mut progress,
+    )
+    .await;
+
+    release_port_job(&flash_jobs_state, &upload_port).await;
+
+    let chip_info = restore_result?;
+
+    progress.complete();
+
+    Ok(chip_info)
+}
+
+/// Arguments of a `restore_uf2_device` call
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Uf2RestoreRequest {
+    pub hw_model: u32,
+    pub upload_port: String,
+    pub backup_file_name: String, // As listed by `get_device_backups`
+}
+
+/// Copies a backup taken from CURRENT.UF2 back onto the device, returning the restored
+/// firmware's serial port
+#[tauri::command]
+pub async fn restore_uf2_device(
+    app_handle: tauri::AppHandle,
+    boards_state: tauri::State<'_, state::BoardsState>,
+    flash_jobs_state: tauri::State<'_, state::FlashJobsState>,
+    request: Uf2RestoreRequest,
+) -> Result<String, String> {
+    log::info!(
+        "Called \"restore_uf2_device\" command with args: request: {:?}",
+        request
+    );
+
+    let board = get_board_by_hw_model(&boards_state, request.hw_model).await?;
+    check_nrf_board(&board)?;
+
+    let backup_directory = create_or_locate_backup_directory(&app_handle).await?;
+    let upload_port = request.upload_port;
+    let mut progress = get_job_progress(&app_handle, &upload_port);
+
+    register_port_job(&flash_jobs_state, &upload_port, &progress).await?;
+
+    let restore_result = restore_uf2_backup(
+        &backup_directory,
+        upload_port.clone(),
+        &board,
+        request.backup_file_name,
+        &mut progress,
+    )
+    .await;
+
+    release_port_job(&flash_jobs_state, &upload_port).await;
+
+    let firmware_port = restore_result?;
+
+    progress.complete();
+
+    Ok(firmware_port)
+}
+
+#[tauri::command]
+pub async fn read_device_identity(
+    app_handle: tauri::AppHandle,
+    boards_state: tauri::State<'_, state::BoardsState>,
+    baud_rate_state: tauri::State<'_, state::BaudRateState>,
+    flash_jobs_state: tauri::State<'_, state::FlashJobsState>,
+    request: EspDeviceRequest,
+) -> Result<EspDeviceIdentity, String> {
+    log::info!(
+        "Called \"read_device_identity\" command with args: request: {:?}",
+        request
+    );
+
+    let (board, connection_config) =
+        get_esp_device(&app_handle, &boards_state, &baud_rate_state, &request).await?;
+
+    let temp_directory = get_temp_directory(&app_handle)?;
+    let upload_port = request.upload_port;
+    let mut progress = get_job_progress(&app_handle, &upload_port);
+
+    register_port_job(&flash_jobs_state, &upload_port, &progress).await?;
+
+    let identity_result = read_esp32_identity(
+        temp_directory,
+        upload_port.clone(),
+        &board,
+        &connection_config,
+        &mut progress,
+    )
+    .await;
+
+    release_port_job(&flash_jobs_state, &upload_port).await;
+
+    let identity = identity_result?;
+
+    progress.complete();
+
+    Ok(identity)
+}
+
+/// Leaves the device in its bootloader, so a following flash or update should use its drive or
+/// the bootloader's serial port
+#[tauri::command]
+pub async fn get_nrf_bootloader_status(
+    app_handle: tauri::AppHandle,
+    boards_state: tauri::State<'_, state::BoardsState>,
+    flash_jobs_state: tauri::State<'_, state::FlashJobsState>,
+    hw_model: u32,
+    upload_port: String,
+) -> Result<NrfBootloaderStatus, String> {
+    log::info!(
+        "Called \"get_nrf_bootloader_status\" command with args: hw_model: {}, upload_port: {}",
+        hw_model,
+        upload_port
+    );
+
+    let board = get_board_by_hw_model(&boards_state, hw_model).await?;
+    check_nrf_board(&board)?;
+
+    let mut progress = get_job_progress(&app_handle, &upload_port);
+
+    register_port_job(&flash_jobs_state, &upload_port, &progress).await?;
+
+    let status_result = read_nrf_bootloader_status(&upload_port, &mut progress).await;
+
+    release_port_job(&flash_jobs_state, &upload_port).await;
+
+    let status = status_result?;
+
+    progress.complete();
+
+    Ok(status)
+}
+
+/// Arguments of an `update_nrf_bootloader` call, the package is picked from the board
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NrfBootloaderUpdateRequest {
+    pub hw_model: u32,
+    pub upload_port: String,
+    pub update: NrfBootloaderUpdate, // As reported by `get_nrf_bootloader_status`
+}
+
+#[tauri::command]
+pub async fn update_nrf_bootloader(
+    app_handle: tauri::AppHandle,
+    boards_state: tauri::State<'_, state::BoardsState>,
+    flash_jobs_state: tauri::State<'_, state::FlashJobsState>,
+    request: NrfBootloaderUpdateRequest,
+) -> Result<Option<Uf2BootloaderInfo>, String> {
+    log::info!(
+        "Called \"update_nrf_bootloader\" command with args: request: {:?}",
+        request
+    );
+
+    let board = get_board_by_hw_model(&boards_state, request.hw_model).await?;
+    check_nrf_board(&board)?;
+
+    let bootloader_directory = locate_bootloader_resource_directory(&app_handle)?;
+    let upload_port = request.upload_port;
+    let mut progress = get_job_progress(&app_handle, &upload_port);
+
+    register_port_job(&flash_jobs_state, &upload_port, &progress).await?;
+
+    let update_result = install_nrf_bootloader_update(
+        &upload_port,
+        &board,
+        &request.update,
+        &bootloader_directory,
+        &mut progress,
+    )
+    .await;
+
+    release_port_job(&flash_jobs_state, &upload_port).await;
+
+    let bootloader_info = update_result?;
+
+    progress.complete();
+
+    Ok(bootloader_info)
 }
 
 #[tauri::command]
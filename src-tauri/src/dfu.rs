@@ -0,0 +1,660 @@
+// Legacy Nordic serial DFU as spoken by the Adafruit nRF52 bootloader, following adafruit-nrfutil
+// https://github.com/adafruit/Adafruit_nRF52_nrfutil/blob/master/nordicsemi/dfu/dfu_transport_serial.py
+
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use serialport::SerialPort;
+
+use crate::api::boards::Board;
+use crate::fs::{create_archive_from_bytes, extract_binary_from_archive};
+use crate::progress::{JobProgress, JobStage};
+use crate::uf2::{
+    get_firmware_usb_ids, list_usb_serial_ports, touch_serial_port, wait_for_firmware_port,
+    FirmwareUsbIds, FIRMWARE_PORT_TIMEOUT,
+};
+
+const DFU_BAUD_RATE: u32 = 115_200;
+const DFU_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+const DFU_SEND_ATTEMPTS: usize = 3;
+const DFU_MANIFEST_FILE_NAME: &str = "manifest.json";
+
+// Packet types at the start of each HCI payload
+const DFU_INIT_PACKET: u32 = 1;
+const DFU_START_PACKET: u32 = 3;
+const DFU_DATA_PACKET: u32 = 4;
+const DFU_STOP_DATA_PACKET: u32 = 5;
+
+const DFU_PACKET_MAX_SIZE: usize = 512;
+
+// The bootloader doesn't acknowledge until the flash operation is queued, not finished, so the
+// host waits out the erase and page write times itself
+const FLASH_PAGE_SIZE: usize = 4096;
+const FLASH_PAGE_ERASE_TIME: Duration = Duration::from_micros(89_700);
+const FLASH_PAGE_WRITE_TIME: Duration = Duration::from_micros(102_400); // 1024 words at 100us
+const MIN_ERASE_WAIT_TIME: Duration = Duration::from_millis(500);
+
+// Three-wire UART HCI framing
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+const HCI_PACKET_TYPE: u8 = 14;
+const HCI_DATA_INTEGRITY_CHECK_PRESENT: u8 = 1;
+const HCI_RELIABLE_PACKET: u8 = 1;
+
+/// Which part of the device a DFU package replaces, as the start packet's update mode
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DfuImageType {
+    SoftDevice = 1,
+    Bootloader = 2,
+    SoftDeviceBootloader = 3,
+    Application = 4,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DfuManifestFile {
+    manifest: DfuManifest,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DfuManifest {
+    application: Option<DfuManifestImage>,
+    bootloader: Option<DfuManifestImage>,
+    softdevice: Option<DfuManifestImage>,
+    softdevice_bootloader: Option<DfuManifestImage>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DfuManifestImage {
+    bin_file: String,
+    dat_file: String,
+    sd_size: Option<u32>, // Combined SoftDevice and bootloader images only
+    bl_size: Option<u32>, // Combined SoftDevice and bootloader images only
+}
+
+/// A single image from an nrfutil DFU `.zip` package
+#[derive(Clone, Debug)]
+pub struct DfuPackage {
+    pub image_type: DfuImageType,
+    pub init_packet: Vec<u8>,
+    pub firmware: Vec<u8>,
+    pub softdevice_size: u32,
+    pub bootloader_size: u32,
+}
+
+impl DfuPackage {
+    fn get_image_sizes(&self) -> (u32, u32, u32) {
+        match self.image_type {
+            DfuImageType::SoftDevice => (self.firmware.len() as u32, 0, 0),
+            DfuImageType::Bootloader => (0, self.firmware.len() as u32, 0),
+            DfuImageType::SoftDeviceBootloader => (self.softdevice_size, self.bootloader_size, 0),
+            DfuImageType::Application => (0, 0, self.firmware.len() as u32),
+        }
+    }
+}
+
+/// Reads the manifest, init packet and firmware from a DFU package on disk
+pub async fn read_dfu_package(package_file_path: &PathBuf) -> Result<DfuPackage, String> {
+    let package_bytes = match tokio::fs::read(package_file_path).await {
+        Ok(package_bytes) => package_bytes,
+        Err(e) => {
+            log::error!(
+                "Error while reading DFU package at {}: {}",
+                package_file_path.display(),
+                e
+            );
+
+            return Err(format!(
+                "Error while reading DFU package at {}: {}",
+                package_file_path.display(),
+                e
+            ));
+        }
+    };
+
+    let mut archive = create_archive_from_bytes(bytes::Bytes::from(package_bytes)).await?;
+
+    let manifest_bytes =
+        extract_binary_from_archive(&mut archive, &DFU_MANIFEST_FILE_NAME.to_string()).await?;
+
+    let manifest = match serde_json::from_slice::<DfuManifestFile>(&manifest_bytes) {
+        Ok(manifest_file) => manifest_file.manifest,
+        Err(e) => {
+            log::error!("Error while parsing DFU package manifest: {}", e);
+            return Err(format!("Error while parsing DFU package manifest: {}", e));
+        }
+    };
+
+    let mut images: Vec<(DfuImageType, DfuManifestImage)> = Vec::new();
+
+    if let Some(image) = manifest.softdevice_bootloader {
+        images.push((DfuImageType::SoftDeviceBootloader, image));
+    }
+    if let Some(image) = manifest.softdevice {
+        images.push((DfuImageType::SoftDevice, image));
+    }
+    if let Some(image) = manifest.bootloader {
+        images.push((DfuImageType::Bootloader, image));
+    }
+    if let Some(image) = manifest.application {
+        images.push((DfuImageType::Application, image));
+    }
+
+    // Each image needs its own DFU session and bootloader restart, which Meshtastic packages
+    // never require
+    let (image_type, image) = match images.len() {
+        1 => images.remove(0),
+        image_count => {
+            log::error!(
+                "DFU package must contain exactly one image, found {}",
+                image_count
+            );
+
+            return Err(format!(
+                "DFU package must contain exactly one image, found {}",
+                image_count
+            ));
+        }
+    };
+
+    let (softdevice_size, bootloader_size) = match (image_type, image.sd_size, image.bl_size) {
+        (DfuImageType::SoftDeviceBootloader, Some(sd_size), Some(bl_size)) => (sd_size, bl_size),
+        (DfuImageType::SoftDeviceBootloader, _, _) => {
+            log::error!("DFU package manifest is missing SoftDevice or bootloader size");
+            return Err(
+                "DFU package manifest is missing SoftDevice or bootloader size".to_string(),
+            );
+        }
+        _ => (0, 0),
+    };
+
+    let package = DfuPackage {
+        image_type,
+        init_packet: extract_binary_from_archive(&mut archive, &image.dat_file).await?,
+        firmware: extract_binary_from_archive(&mut archive, &image.bin_file).await?,
+        softdevice_size,
+        bootloader_size,
+    };
+
+    log::info!(
+        "Read {:?} DFU package with {} byte image from {}",
+        package.image_type,
+        package.firmware.len(),
+        package_file_path.display()
+    );
+
+    Ok(package)
+}
+
+/// CRC-16/CCITT variant used by the Nordic HCI transport
+fn calculate_crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+
+    for byte in data {
+        crc = crc.rotate_left(8);
+        crc ^= *byte as u16;
+        crc ^= (crc & 0x00FF) >> 4;
+        crc ^= crc << 12;
+        crc ^= (crc & 0x00FF) << 5;
+    }
+
+    crc
+}
+
+fn slip_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len() + 2);
+    encoded.push(SLIP_END);
+
+    for byte in data {
+        match *byte {
+            SLIP_END => encoded.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+            SLIP_ESC => encoded.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+            byte => encoded.push(byte),
+        }
+    }
+
+    encoded.push(SLIP_END);
+    encoded
+}
+
+fn slip_decode(data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut escaped = false;
+
+    for byte in data {
+        match (escaped, *byte) {
+            (false, SLIP_END) => (),
+            (false, SLIP_ESC) => escaped = true,
+            (true, SLIP_ESC_END) => {
+                decoded.push(SLIP_END);
+                escaped = false;
+            }
+            (true, SLIP_ESC_ESC) => {
+                decoded.push(SLIP_ESC);
+                escaped = false;
+            }
+            (_, byte) => {
+                decoded.push(byte);
+                escaped = false;
+            }
+        }
+    }
+
+    decoded
+}
+
+/// Wraps a payload in a reliable HCI packet with header checksum and CRC
+fn build_hci_packet(sequence_number: u8, payload: &[u8]) -> Vec<u8> {
+    let mut header = [
+        sequence_number
+            | (((sequence_number + 1) % 8) << 3)
+            | (HCI_DATA_INTEGRITY_CHECK_PRESENT << 6)
+            | (HCI_RELIABLE_PACKET << 7),
+        HCI_PACKET_TYPE | (((payload.len() & 0x000F) as u8) << 4),
+        ((payload.len() & 0x0FF0) >> 4) as u8,
+        0,
+    ];
+    header[3] = header[..3]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg();
+
+    let mut packet = Vec::with_capacity(header.len() + payload.len() + 2);
+    packet.extend_from_slice(&header);
+    packet.extend_from_slice(payload);
+    packet.extend_from_slice(&calculate_crc16(&packet).to_le_bytes());
+
+    slip_encode(&packet)
+}
+
+/// Acknowledgement number from the header of a SLIP frame sent by the bootloader
+fn get_ack_number(frame: &[u8]) -> Option<u8> {
+    slip_decode(frame)
+        .first()
+        .map(|header| (header >> 3) & 0x07)
+}
+
+/// A serial connection to a bootloader waiting in serial DFU mode
+struct DfuTransport {
+    upload_port: String,
+    serial_port: Box<dyn SerialPort>,
+    sequence_number: u8,
+}
+
+impl DfuTransport {
+    fn open(upload_port: &String) -> Result<Self, String> {
+        let serial_port = match serialport::new(upload_port, DFU_BAUD_RATE)
+            .timeout(DFU_ACK_TIMEOUT)
+            .open()
+        {
+            Ok(serial_port) => serial_port,
+            Err(e) => {
+                log::error!(
+                    "Error while opening serial port {} for DFU: {}",
+                    upload_port,
+                    e
+                );
+
+                return Err(format!(
+                    "Error while opening serial port {} for DFU: {}",
+                    upload_port, e
+                ));
+            }
+        };
+
+        Ok(DfuTransport {
+            upload_port: upload_port.clone(),
+            serial_port,
+            sequence_number: 0,
+        })
+    }
+
+    /// Reads one SLIP frame and returns the acknowledgement number from its header
+    fn read_ack_number(&mut self) -> Result<u8, String> {
+        let started_at = Instant::now();
+        let mut frame: Vec<u8> = Vec::new();
+        let mut byte = [0u8; 1];
+
+        while frame.iter().filter(|byte| **byte == SLIP_END).count() < 2 {
+            if started_at.elapsed() > DFU_ACK_TIMEOUT {
+                break;
+            }
+
+            match self.serial_port.read(&mut byte) {
+                Ok(1) => frame.push(byte[0]),
+                Ok(_) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => (),
+                Err(e) => {
+                    log::error!(
+                        "Error while reading DFU acknowledgement on port {}: {}",
+                        self.upload_port,
+                        e
+                    );
+
+                    return Err(format!(
+                        "Error while reading DFU acknowledgement on port {}: {}",
+                        self.upload_port, e
+                    ));
+                }
+            };
+        }
+
+        match get_ack_number(&frame) {
+            Some(ack_number) => Ok(ack_number),
+            None => {
+                log::error!(
+                    "No DFU acknowledgement received on port {}",
+                    self.upload_port
+                );
+
+                Err(format!(
+                    "No DFU acknowledgement received on port {}. Check that the device is in its bootloader.",
+                    self.upload_port
+                ))
+            }
+        }
+    }
+
+    /// Sends a payload, resending it until the bootloader acknowledges its sequence number
+    fn send_packet(&mut self, payload: &[u8]) -> Result<(), String> {
+        self.sequence_number = (self.sequence_number + 1) % 8;
+
+        let packet = build_hci_packet(self.sequence_number, payload);
+        let expected_ack_number = (self.sequence_number + 1) % 8;
+
+        for attempt in 1..=DFU_SEND_ATTEMPTS {
+            match self.serial_port.write_all(&packet) {
+                Ok(_) => (),
+                Err(e) => {
+                    log::error!(
+                        "Error while writing DFU packet to port {}: {}",
+                        self.upload_port,
+                        e
+                    );
+
+                    return Err(format!(
+                        "Error while writing DFU packet to port {}: {}",
+                        self.upload_port, e
+                    ));
+                }
+            };
+
+            let ack_number = self.read_ack_number()?;
+
+            if ack_number == expected_ack_number {
+                return Ok(());
+            }
+
+            log::warn!(
+                "DFU packet {} acknowledged with {}, resending ({}/{})",
+                self.sequence_number,
+                ack_number,
+                attempt,
+                DFU_SEND_ATTEMPTS
+            );
+        }
+
+        log::error!(
+            "DFU packet {} was not acknowledged after {} attempts",
+            self.sequence_number,
+            DFU_SEND_ATTEMPTS
+        );
+
+        Err(format!(
+            "DFU packet {} was not acknowledged after {} attempts",
+            self.sequence_number, DFU_SEND_ATTEMPTS
+        ))
+    }
+
+    /// Runs `send_packet` on the blocking thread pool, since every acknowledgement read blocks
+    /// for up to `DFU_ACK_TIMEOUT`
+    async fn send_packet_blocking(mut self, payload: Vec<u8>) -> Result<Self, String> {
+        match tokio::task::spawn_blocking(move || self.send_packet(&payload).map(|_| self)).await {
+            Ok(result) => result,
+            Err(e) => {
+                log::error!("Error while sending DFU packet: {}", e);
+                Err(format!("Error while sending DFU packet: {}", e))
+            }
+        }
+    }
+}
+
+/// Touches the firmware on `upload_port` into its bootloader and returns the port the bootloader
+/// enumerates as
+///
+/// A board already put into the bootloader by hand doesn't re-enumerate, so `upload_port` is
+/// used as is when no new port appears.
+pub(crate) async fn enter_serial_dfu(
+    upload_port: &String,
+    firmware_usb_ids: &FirmwareUsbIds,
+    progress: &mut JobProgress,
+) -> Result<String, String> {
+    let firmware_ports = list_usb_serial_ports()?;
+
+    touch_serial_port(upload_port).await?;
+
+    // The bootloader shares the firmware's vendor ID but not its product ID
+    let bootloader_usb_ids = FirmwareUsbIds {
+        vid: firmware_usb_ids.vid,
+        pid: None,
+    };
+
+    match wait_for_firmware_port(&bootloader_usb_ids, &firmware_ports, progress).await? {
+        Some(dfu_port) => Ok(dfu_port),
+        None => {
+            log::warn!(
+                "No new serial port appeared after touching {}, assuming it is already in the bootloader",
+                upload_port
+            );
+
+            Ok(upload_port.clone())
+        }
+    }
+}
+
+/// Flashes a DFU package to the nRF52 on `upload_port` through its Adafruit bootloader,
+/// returning the port the firmware comes back on
+pub async fn flash_nrf_serial_dfu(
+    upload_port: &String,
+    package_file_path: &PathBuf,
+    board: &Board,
+    progress: &mut JobProgress,
+) -> Result<String, String> {
+    // Read before touching the device, so a bad package doesn't strand it in the bootloader
+    let package = read_dfu_package(package_file_path).await?;
+
+    progress.start_stage(JobStage::Connect);
+
+    // Only readable while the firmware still owns the port
+    let firmware_usb_ids = get_firmware_usb_ids(upload_port, board)?;
+    let dfu_port = enter_serial_dfu(upload_port, &firmware_usb_ids, progress).await?;
+
+    // The bootloader's own port must not be mistaken for the firmware's once it reboots
+    let bootloader_ports = list_usb_serial_ports()?;
+
+    send_dfu_package(&dfu_port, &package, progress).await?;
+
+    if let Some(port_name) =
+        wait_for_firmware_port(&firmware_usb_ids, &bootloader_ports, progress).await?
+    {
+        return Ok(port_name);
+    }
+
+    log::error!(
+        "No serial port with USB IDs {:?} appeared within {:?} of the DFU transfer",
+        firmware_usb_ids,
+        FIRMWARE_PORT_TIMEOUT
+    );
+
+    Err(format!(
+        "The bootloader accepted the DFU package, but no serial port with vendor ID {:04x} appeared within {} seconds. The firmware may be crashing on boot; check the device's screen or LEDs, or reconnect it and try again.",
+        firmware_usb_ids.vid,
+        FIRMWARE_PORT_TIMEOUT.as_secs()
+    ))
+}
+
+/// Sends a DFU package to an Adafruit bootloader already waiting in serial DFU on `dfu_port`
+pub async fn send_dfu_package(
+    dfu_port: &String,
+    package: &DfuPackage,
+    progress: &mut JobProgress,
+) -> Result<(), String> {
+    let (softdevice_size, bootloader_size, app_size) = package.get_image_sizes();
+
+    let mut transport = DfuTransport::open(dfu_port)?;
+
+    log::info!("Opened DFU transport on port {}", dfu_port);
+
+    // The bootloader erases every page the new image covers before acknowledging data
+    progress.start_stage(JobStage::Erase);
+
+    let mut start_packet = DFU_START_PACKET.to_le_bytes().to_vec();
+    start_packet.extend_from_slice(&(package.image_type as u32).to_le_bytes());
+    start_packet.extend_from_slice(&softdevice_size.to_le_bytes());
+    start_packet.extend_from_slice(&bootloader_size.to_le_bytes());
+    start_packet.extend_from_slice(&app_size.to_le_bytes());
+    transport = transport.send_packet_blocking(start_packet).await?;
+
+    let total_size = (softdevice_size + bootloader_size + app_size) as usize;
+    let erase_pages = (total_size / FLASH_PAGE_SIZE + 1) as u32;
+    tokio::time::sleep((FLASH_PAGE_ERASE_TIME * erase_pages).max(MIN_ERASE_WAIT_TIME)).await;
+
+    let mut init_packet = DFU_INIT_PACKET.to_le_bytes().to_vec();
+    init_packet.extend_from_slice(&package.init_packet);
+    init_packet.extend_from_slice(&[0x00, 0x00]); // Padding the bootloader expects
+    transport = transport.send_packet_blocking(init_packet).await?;
+
+    progress.plan_images(vec![package.firmware.len()]);
+    progress.start_stage(JobStage::Write {
+        image_index: 1,
+        image_count: 1,
+        image_name: format!("{:?}", package.image_type),
+    });
+
+    let mut bytes_sent = 0;
+
+    for (index, data_chunk) in package.firmware.chunks(DFU_PACKET_MAX_SIZE).enumerate() {
+        // The bootloader keeps the old image marked invalid until the new one passes its CRC
+        // check, so stopping between packets leaves the device waiting in the bootloader
+        progress.check_cancelled()?;
+
+        let mut data_packet = DFU_DATA_PACKET.to_le_bytes().to_vec();
+        data_packet.extend_from_slice(data_chunk);
+        transport = transport.send_packet_blocking(data_packet).await?;
+
+        bytes_sent += data_chunk.len();
+        progress.update(bytes_sent, package.firmware.len());
+
+        if (index + 1) % (FLASH_PAGE_SIZE / DFU_PACKET_MAX_SIZE) == 0 {
+            tokio::time::sleep(FLASH_PAGE_WRITE_TIME).await;
+        }
+    }
+
+    // Let the last page land before asking the bootloader to validate the image
+    tokio::time::sleep(FLASH_PAGE_WRITE_TIME).await;
+
+    // The bootloader validates the image and resets into it once it acknowledges the stop packet
+    progress.start_stage(JobStage::Reboot);
+
+    transport
+        .send_packet_blocking(DFU_STOP_DATA_PACKET.to_le_bytes().to_vec())
+        .await?;
+
+    log::info!(
+        "Sent {} byte {:?} image over serial DFU on port {}",
+        bytes_sent,
+        package.image_type,
+        dfu_port
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calculates_crc16_check_value() {
+        // CRC-16/CCITT-FALSE check value
+        assert_eq!(calculate_crc16(b"123456789"), 0x29B1);
+        assert_eq!(calculate_crc16(&[]), 0xFFFF);
+    }
+
+    #[test]
+    fn slip_encodes_frame_and_escapes() {
+        assert_eq!(
+            slip_encode(&[0x01, SLIP_END, 0x02, SLIP_ESC]),
+            vec![
+                SLIP_END,
+                0x01,
+                SLIP_ESC,
+                SLIP_ESC_END,
+                0x02,
+                SLIP_ESC,
+                SLIP_ESC_ESC,
+                SLIP_END
+            ]
+        );
+    }
+
+    #[test]
+    fn slip_decode_reverses_encode() {
+        let data: Vec<u8> = (0..=255).collect();
+
+        assert_eq!(slip_decode(&slip_encode(&data)), data);
+    }
+
+    #[test]
+    fn slip_decode_keeps_unknown_escapes() {
+        assert_eq!(
+            slip_decode(&[SLIP_END, SLIP_ESC, 0x01, SLIP_END]),
+            vec![0x01]
+        );
+    }
+
+    #[test]
+    fn builds_hci_packet() {
+        let payload = [0x04, 0x00, 0x00, 0x00];
+        let packet = slip_decode(&build_hci_packet(1, &payload));
+
+        // Sequence 1, acknowledging 2, with integrity check and reliable flags
+        assert_eq!(packet[0], 0xD1);
+        // Packet type 14 and the low nibble of the 4 byte length
+        assert_eq!(packet[1], 0x4E);
+        assert_eq!(packet[2], 0x00);
+        // Header bytes sum to zero
+        assert_eq!(
+            packet[..4]
+                .iter()
+                .fold(0u8, |sum, byte| sum.wrapping_add(*byte)),
+            0
+        );
+        assert_eq!(&packet[4..8], &payload);
+        assert_eq!(&packet[8..], &calculate_crc16(&packet[..8]).to_le_bytes());
+    }
+
+    #[test]
+    fn encodes_long_payload_length_across_header_bytes() {
+        let payload = vec![0u8; DFU_PACKET_MAX_SIZE];
+        let packet = slip_decode(&build_hci_packet(7, &payload));
+
+        // Sequence 7 acknowledges 0
+        assert_eq!(packet[0] & 0x3F, 0x07);
+        assert_eq!(packet[1] >> 4, (DFU_PACKET_MAX_SIZE & 0x0F) as u8);
+        assert_eq!(packet[2], (DFU_PACKET_MAX_SIZE >> 4) as u8);
+        assert_eq!(packet.len(), 4 + DFU_PACKET_MAX_SIZE + 2);
+    }
+
+    #[test]
+    fn reads_ack_number_from_frame() {
+        // Acknowledgement packets carry only a header, with the ack number in bits 3 to 5
+        let frame = slip_encode(&[0x02 << 3, 0x00, 0x00, 0x00]);
+
+        assert_eq!(get_ack_number(&frame), Some(2));
+        assert_eq!(get_ack_number(&[SLIP_END, SLIP_END]), None);
+    }
+}
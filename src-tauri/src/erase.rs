@@ -0,0 +1,127 @@
+use std::path::PathBuf;
+
+use crate::api::boards::Board;
+use crate::chip::EspChipInfo;
+use crate::flasher::EspConnectionConfig;
+use crate::partitions::read_partition_table_from_device;
+use crate::progress::{JobProgress, JobStage};
+use crate::security::check_plaintext_writable;
+use crate::session::EspFlashSession;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EraseTarget {
+    /// Wipes the entire flash, including bootloader and app
+    FullChip,
+    /// Wipes stored preferences and Bluetooth pairings
+    Nvs,
+    /// Wipes config and node database (factory reset) while keeping the app
+    Filesystem,
+}
+
+pub async fn erase_esp32(
+    temp_directory: PathBuf,
+    upload_port: String,
+    board: &Board,
+    erase_target: EraseTarget,
+    required_flash_size: u32,
+    connection_config: &EspConnectionConfig,
+    progress: &mut JobProgress,
+) -> Result<EspChipInfo, String> {
+    let mut session = EspFlashSession::connect(
+        temp_directory,
+        &upload_port,
+        board,
+        required_flash_size,
+        connection_config,
+        progress,
+    )
+    .await?;
+
+    // A secured device can't be written again in plaintext once erased
+    check_plaintext_writable(&session.chip_info().security)?;
+
+    erase_esp32_session(&mut session, erase_target, progress).await?;
+
+    let flash_report = session.reset(progress)?;
+
+    Ok(flash_report.chip_info)
+}
+
+/// Erases part or all of the flash over an already open session, without resetting the device
+pub async fn erase_esp32_session(
+    session: &mut EspFlashSession,
+    erase_target: EraseTarget,
+    progress: &mut JobProgress,
+) -> Result<(), String> {
+    let temp_directory = session.temp_directory().clone();
+    let upload_port = session.upload_port().clone();
+
+    progress.start_stage(JobStage::Erase);
+
+    // Erase commands don't report progress, so the stage jumps from empty to done
+    let erase_size = if let EraseTarget::FullChip = erase_target {
+        log::info!("Erasing entire flash on port {}...", upload_port);
+
+        match session.flasher()?.erase_flash() {
+            Ok(_) => (),
+            Err(e) => {
+                log::error!("Error while erasing flash on port {}: {}", upload_port, e);
+                return Err(format!(
+                    "Error while erasing flash on port {}: {}",
+                    upload_port, e
+                ));
+            }
+        };
+
+        session.chip_info().flash_size_bytes
+    } else {
+        let partition_table =
+            read_partition_table_from_device(&temp_directory, session.flasher()?).await?;
+
+        let partition = match erase_target {
+            EraseTarget::Nvs => partition_table.nvs_partition()?,
+            _ => partition_table.filesystem_partition()?,
+        };
+
+        log::info!(
+            "Erasing partition \"{}\" (0x{:08x}..0x{:08x}) on port {}...",
+            partition.label,
+            partition.offset,
+            partition.end(),
+            upload_port
+        );
+
+        match session
+            .flasher()?
+            .erase_region(partition.offset, partition.size)
+        {
+            Ok(_) => (),
+            Err(e) => {
+                log::error!(
+                    "Error while erasing partition \"{}\" on port {}: {}",
+                    partition.label,
+                    upload_port,
+                    e
+                );
+
+                return Err(format!(
+                    "Error while erasing partition \"{}\" on port {}: {}",
+                    partition.label, upload_port, e
+                ));
+            }
+        };
+
+        partition.size
+    };
+
+    progress.update(erase_size as usize, erase_size as usize);
+
+    log::info!(
+        "Successfully erased {:?} on port {}",
+        erase_target,
+        upload_port
+    );
+
+    Ok(())
+}
@@ -1,9 +1,24 @@
-use std::path::{Path, PathBuf};
-
-use tauri::Manager;
-use tokio::fs::File;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use crate::api::boards::Board;
+use crate::backup::{backup_esp32_session, backup_uf2_device, BackupMetadata};
+use crate::bootloader::{check_nrf_bootloader_compatible, get_nrf_bootloader_status};
+use crate::chip::{check_esp_image, get_esp_target, EspChipInfo};
+use crate::dfu::flash_nrf_serial_dfu;
+use crate::erase::{erase_esp32_session, EraseTarget};
+use crate::identity::EspDeviceIdentity;
+use crate::partitions::{
+    parse_partition_table_from_image, read_partition_table_from_device, Partition,
+};
+use crate::progress::JobProgress;
+use crate::rp2040::{flash_rp2040, Rp2040FlashPlan};
+use crate::security::check_plaintext_writable;
+use crate::session::{EspFlashReport, EspFlashSession, EspImage};
+use crate::uf2::{
+    copy_uf2_file, erase_uf2_device, get_firmware_usb_ids, get_uf2_drive, list_usb_serial_ports,
+    read_uf2_bootloader_info, wait_for_uf2_reboot,
+};
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -98,103 +113,261 @@ pub fn parse_firmware_version(firmware_version_id: &String) -> Result<FirmwareVe
     })
 }
 
-#[derive(Clone, Debug)]
-struct FlashProgress {
-    current: usize,
-    total: usize,
-    app_handle: tauri::AppHandle,
-    board_id: BoardId,
-}
+pub const FALLBACK_ESP_BAUD_RATE: u32 = 115_200;
+pub const DEFAULT_ESP_BAUD_RATE: u32 = 460_800;
+pub const SUPPORTED_ESP_BAUD_RATES: [u32; 6] =
+    [115_200, 230_400, 460_800, 921_600, 1_500_000, 2_000_000];
+pub const DEFAULT_ESP_WRITE_RETRIES: u32 = 3;
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub struct BoardId(String);
+// Built-in USB-Serial-JTAG peripheral on ESP32-S3, C3, C6 and H2
+const ESPRESSIF_USB_VID: u16 = 0x303A;
+const USB_SERIAL_JTAG_PID: u16 = 0x1001;
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub struct FlashStatusUpdate {
-    board_id: BoardId,
-    current: usize,
-    total: usize,
+const RESET_LINE_DELAY: Duration = Duration::from_millis(100);
+
+/// How the host puts the device into its ROM bootloader before connecting
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EspResetStrategy {
+    /// DTR / RTS driving IO0 and EN through the auto-reset circuit of a USB-UART bridge
+    Classic,
+    /// DTR / RTS sequence understood by the chip's built-in USB-Serial-JTAG peripheral
+    UsbJtag,
+    /// Leaves the reset lines alone, for devices already put into the bootloader by hand
+    NoReset,
 }
 
-impl espflash::flasher::ProgressCallbacks for FlashProgress {
-    fn init(&mut self, addr: u32, total: usize) {
-        log::info!(
-            "Initializing flash progress with addr: {}, total: {}",
-            addr,
-            total
-        );
+const RESET_STRATEGY_ORDER: [EspResetStrategy; 3] = [
+    EspResetStrategy::Classic,
+    EspResetStrategy::UsbJtag,
+    EspResetStrategy::NoReset,
+];
+
+impl EspResetStrategy {
+    /// DTR and RTS pins handed to espflash's serial interface for this strategy
+    fn get_reset_pins(&self) -> (Option<u8>, Option<u8>) {
+        match self {
+            EspResetStrategy::Classic => (Some(1), Some(0)),
+            EspResetStrategy::UsbJtag | EspResetStrategy::NoReset => (None, None),
+        }
+    }
 
-        self.current = 0;
-        self.total = total;
+    /// Runs any reset sequence espflash doesn't perform itself when connecting
+    async fn reset_to_bootloader(
+        &self,
+        serial_interface: &mut espflash::interface::Interface,
+    ) -> Result<(), serialport::Error> {
+        if let EspResetStrategy::UsbJtag = self {
+            // Mirrors esptool's USBJTAGSerialReset
+            serial_interface.write_request_to_send(false)?;
+            serial_interface.write_data_terminal_ready(false)?;
+            tokio::time::sleep(RESET_LINE_DELAY).await;
+
+            serial_interface.write_data_terminal_ready(true)?;
+            serial_interface.write_request_to_send(false)?;
+            tokio::time::sleep(RESET_LINE_DELAY).await;
+
+            serial_interface.write_request_to_send(true)?;
+            serial_interface.write_data_terminal_ready(false)?;
+            serial_interface.write_request_to_send(true)?;
+            tokio::time::sleep(RESET_LINE_DELAY).await;
+
+            serial_interface.write_data_terminal_ready(false)?;
+            serial_interface.write_request_to_send(false)?;
+        }
+
+        Ok(())
     }
+}
 
-    fn update(&mut self, current: usize) {
-        log::info!("Updating flash progress with current: {}", current);
-        self.current = current;
-
-        match self.app_handle.emit_all(
-            format!("flash-status-update-{}", self.board_id.0).as_str(),
-            FlashStatusUpdate {
-                board_id: self.board_id.clone(),
-                current,
-                total: self.total,
-            },
-        ) {
-            Ok(_) => (),
-            Err(e) => {
-                log::error!("Error while emitting flash status update: {}", e);
-                panic!("Error while emitting flash status update");
-            }
-        };
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EspConnectionConfig {
+    pub baud_rate: u32,
+    pub max_write_retries: u32, // Per chunk, reconnecting before each retry
+    pub reset_strategy: Option<EspResetStrategy>, // Detected from the port when unset
+}
+
+impl Default for EspConnectionConfig {
+    fn default() -> Self {
+        EspConnectionConfig {
+            baud_rate: DEFAULT_ESP_BAUD_RATE,
+            max_write_retries: DEFAULT_ESP_WRITE_RETRIES,
+            reset_strategy: None,
+        }
     }
+}
+
+impl EspConnectionConfig {
+    pub fn with_baud_rate(baud_rate: u32) -> Result<Self, String> {
+        if !SUPPORTED_ESP_BAUD_RATES.contains(&baud_rate) {
+            log::error!(
+                "Unsupported baud rate {}, expected one of {:?}",
+                baud_rate,
+                SUPPORTED_ESP_BAUD_RATES
+            );
+
+            return Err(format!(
+                "Unsupported baud rate {}, expected one of {:?}",
+                baud_rate, SUPPORTED_ESP_BAUD_RATES
+            ));
+        }
 
-    fn finish(&mut self) {
-        log::info!("Successfully flashed firmware chunk");
+        Ok(EspConnectionConfig {
+            baud_rate,
+            ..Default::default()
+        })
     }
 }
 
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FlashMode {
+    /// Writes only the app partition, keeping the device's config and node database
+    #[default]
+    Update,
+    /// Erases the whole chip, then writes bootloader, partitions, app, OTA and filesystem
+    FactoryInstall,
+}
+
+/// How firmware reaches an nRF52 board
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NrfUploadMethod {
+    /// Copies the UF2 image onto the bootloader's mass storage drive at `upload_port`
+    #[default]
+    Uf2Copy,
+    /// Sends the DFU package over the bootloader's serial port at `upload_port`
+    SerialDfu,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlashResult {
+    pub flash_mode: FlashMode,
+    pub verified: bool,                             // ESP32 variants only
+    pub chip_info: Option<EspChipInfo>,             // ESP32 variants only
+    pub baud_rate: Option<u32>,                     // ESP32 variants only
+    pub reset_strategy: Option<EspResetStrategy>,   // ESP32 variants only
+    pub throughput_bytes_per_second: Option<u64>,   // ESP32 variants only
+    pub identity: Option<EspDeviceIdentity>,        // ESP32 variants only
+    pub backup: Option<BackupMetadata>,             // Only set when a pre-flash backup was taken
+    pub firmware_port: Option<String>,              // nRF52 and RP2040 only
+    pub rp2040_flash_plan: Option<Rp2040FlashPlan>, // RP2040 variants only
+}
+
+/// Temp files a flash job extracted from the release, used by the architectures noted
+#[derive(Clone, Debug)]
+pub struct FlashFiles {
+    pub temp_directory: PathBuf, // Scratch space for flash readback, ESP32 variants only
+    pub firmware_file_name: String,
+    pub firmware_file_path: PathBuf,
+    pub update_file_path: PathBuf,            // ESP32 variants only
+    pub ble_ota_file_path: Option<PathBuf>,   // ESP32 variants with a BLE OTA binary only
+    pub littlefs_file_path: PathBuf,          // ESP32 variants only
+    pub dfu_package_file_path: PathBuf,       // nRF52 serial DFU only
+    pub uf2_erase_file_path: Option<PathBuf>, // UF2 variants only, erases flash when set
+    pub firmware_version: FirmwareVersion,    // Version the files were released as
+}
+
+/// How a flash job writes the device, used by the architectures noted
+#[derive(Clone, Debug)]
+pub struct FlashOptions {
+    pub flash_mode: FlashMode,
+    pub verify: bool,                           // ESP32 variants only
+    pub backup_directory: Option<PathBuf>, // Backs up before flashing when set, ESP32 and nRF52 UF2 only
+    pub connection_config: EspConnectionConfig, // ESP32 variants only
+    pub nrf_upload_method: NrfUploadMethod, // nRF52 variants only
+    pub ignore_bootloader_check: bool,     // nRF52 UF2 copy only
+    pub ignore_binary_info_check: bool,    // RP2040 variants only
+}
+
 pub async fn flash_board(
-    app_handle: tauri::AppHandle,
-    temp_firmware_file_path: PathBuf,
-    temp_ble_ota_file_path: PathBuf,  // ESP32 variants only
-    temp_littlefs_file_path: PathBuf, // ESP32 variants only
-    firmware_file_name: String,
+    files: FlashFiles,
     upload_port: String,
     board: Board,
-) -> Result<(), String> {
-    log::debug!("Flashing board with architecture {}", board.architecture);
+    options: FlashOptions,
+    progress: &mut JobProgress,
+) -> Result<FlashResult, String> {
+    log::debug!(
+        "Flashing board with architecture {} in mode {:?}",
+        board.architecture,
+        options.flash_mode
+    );
+
+    let mut flash_result = FlashResult {
+        flash_mode: options.flash_mode.clone(),
+        ..Default::default()
+    };
 
     if board.architecture.contains("esp") {
         log::info!(
             "ESP32 board detected, will use firmware file: {} -> {}",
-            firmware_file_name,
+            files.firmware_file_name,
             upload_port
         );
 
-        flash_esp32(
-            app_handle,
-            temp_firmware_file_path.clone(),
-            temp_ble_ota_file_path,
-            temp_littlefs_file_path,
-            upload_port,
-        )
-        .await?;
-    } else if board.architecture.contains("nrf") {
+        let flash_report = flash_esp32(&files, upload_port, &board, &options, progress).await?;
+
         log::info!(
-            "NRF board detected, will use firmware file: {} -> {}",
-            firmware_file_name,
-            upload_port
+            "Wrote {} bytes in {:?} at {} baud ({} bytes/s)",
+            flash_report.bytes_written,
+            flash_report.elapsed,
+            flash_report.baud_rate,
+            flash_report.throughput_bytes_per_second()
         );
 
-        flash_nrf(firmware_file_name, temp_firmware_file_path, upload_port).await?;
+        flash_result.verified = flash_report.verified;
+        flash_result.baud_rate = Some(flash_report.baud_rate);
+        flash_result.reset_strategy = Some(flash_report.reset_strategy);
+        flash_result.throughput_bytes_per_second = Some(flash_report.throughput_bytes_per_second());
+        flash_result.chip_info = Some(flash_report.chip_info);
+        flash_result.backup = flash_report.backup;
+        flash_result.identity = Some(flash_report.identity);
+    } else if board.architecture.contains("nrf") {
+        match options.nrf_upload_method {
+            NrfUploadMethod::Uf2Copy => {
+                log::info!(
+                    "NRF board detected, will use firmware file: {} -> {}",
+                    files.firmware_file_name,
+                    upload_port
+                );
+
+                let (firmware_port, backup_metadata) =
+                    flash_nrf(&files, upload_port, &board, &options, progress).await?;
+
+                flash_result.firmware_port = Some(firmware_port);
+                flash_result.backup = backup_metadata;
+            }
+            NrfUploadMethod::SerialDfu => {
+                log::info!(
+                    "NRF board detected, will use DFU package: {} -> {}",
+                    files.dfu_package_file_path.display(),
+                    upload_port
+                );
+
+                let firmware_port = flash_nrf_serial_dfu(
+                    &upload_port,
+                    &files.dfu_package_file_path,
+                    &board,
+                    progress,
+                )
+                .await?;
+
+                flash_result.firmware_port = Some(firmware_port);
+            }
+        }
     } else if board.architecture.contains("rp2040") {
         log::info!(
-            "Pico board detected, will use firmware file: {} -> {}",
-            firmware_file_name,
+            "RP2040 board detected, will use firmware file: {} -> {}",
+            files.firmware_file_name,
             upload_port
         );
 
-        flash_nrf(firmware_file_name, temp_firmware_file_path, upload_port).await?;
+        let flash_report = flash_rp2040(&files, upload_port, &board, &options, progress).await?;
+
+        flash_result.firmware_port = Some(flash_report.firmware_port);
+        flash_result.rp2040_flash_plan = Some(flash_report.flash_plan);
     } else {
         log::error!("Unsupported architecture: {}", board.architecture);
         return Err(format!("Unsupported architecture: {}", board.architecture));
@@ -202,7 +375,7 @@ pub async fn flash_board(
 
     log::info!("Successfully flashed firmware");
 
-    Ok(())
+    Ok(flash_result)
 }
 
 pub fn get_port_by_name(port: &String) -> Result<serialport::SerialPortInfo, String> {
@@ -237,120 +410,172 @@ pub fn get_port_by_name(port: &String) -> Result<serialport::SerialPortInfo, Str
     Ok(port_info)
 }
 
-pub async fn flash_esp_binary(
-    app_handle: tauri::AppHandle,
-    upload_port: String,
-    flash_offset: u32,
-    binary_file_path: PathBuf,
-    reboot: bool,
-) -> Result<(), String> {
-    let serial_interface = init_esp32_serial_port(&upload_port).await?;
-    let usb_port_info = get_serial_port_info(&upload_port).await?;
-
-    let mut binary_data_buffer = match tokio::fs::read(&binary_file_path).await {
-        Ok(data) => data,
-        Err(e) => {
-            log::error!(
-                "Error while reading firmware file at {}: {}",
-                binary_file_path.display(),
-                e.to_string()
-            );
-
-            return Err(format!(
-                "Error while reading firmware file at {}: {}",
-                binary_file_path.display(),
-                e
-            ));
-        }
-    };
-
-    log::info!("Connecting to port {}...", upload_port);
+/// Reset strategies to try in order, starting with the configured or detected one
+async fn get_reset_strategy_sequence(
+    upload_port: &String,
+    connection_config: &EspConnectionConfig,
+) -> Result<Vec<EspResetStrategy>, String> {
+    let first_strategy = match connection_config.reset_strategy {
+        Some(reset_strategy) => reset_strategy,
+        None => {
+            let usb_port_info = get_serial_port_info(upload_port).await?;
 
-    let mut flasher = match espflash::flasher::Flasher::connect(
-        serial_interface,
-        usb_port_info,
-        Some(115_200),
-        true,
-    ) {
-        Ok(flasher) => flasher,
-        Err(e) => {
-            log::error!("Error while connecting to port {}: {}", upload_port, e);
-            return Err(format!(
-                "Error while connecting to port {}: {}",
-                upload_port, e
-            ));
+            if usb_port_info.vid == ESPRESSIF_USB_VID && usb_port_info.pid == USB_SERIAL_JTAG_PID {
+                EspResetStrategy::UsbJtag
+            } else {
+                EspResetStrategy::Classic
+            }
         }
     };
 
-    log::info!("Starting flashing process...");
+    let mut reset_strategies = vec![first_strategy];
 
-    let chunk_size = 1024 * 1024; // 1MB chunk size
-    let mut current_flash_offset = flash_offset;
+    reset_strategies.extend(
+        RESET_STRATEGY_ORDER
+            .iter()
+            .filter(|reset_strategy| **reset_strategy != first_strategy),
+    );
 
-    log::debug!("Flashing buffer with length {}", binary_data_buffer.len());
+    Ok(reset_strategies)
+}
 
-    while !binary_data_buffer.is_empty() {
-        let (data_chunk, remaining_data) = if binary_data_buffer.len() > chunk_size {
-            binary_data_buffer.split_at(chunk_size)
-        } else {
-            (binary_data_buffer.as_ref(), &[][..])
+/// Connects with the first reset strategy that reaches the bootloader, falling back through
+/// the others and finally asking the user to enter the bootloader by hand
+pub async fn connect_esp32_flasher(
+    upload_port: &String,
+    connection_config: &EspConnectionConfig,
+) -> Result<(espflash::flasher::Flasher, u32, EspResetStrategy), String> {
+    let reset_strategies = get_reset_strategy_sequence(upload_port, connection_config).await?;
+
+    for reset_strategy in reset_strategies {
+        match connect_esp32_flasher_with_reset(upload_port, connection_config, &reset_strategy)
+            .await
+        {
+            Ok((flasher, baud_rate)) => return Ok((flasher, baud_rate, reset_strategy)),
+            Err(e) => {
+                log::warn!(
+                    "Could not connect to port {} with reset strategy {:?}: {}",
+                    upload_port,
+                    reset_strategy,
+                    e
+                );
+            }
         };
+    }
 
-        log::debug!(
-            "Flashing {} byte chunk at address {}, {} bytes remaining in buffer",
-            data_chunk.len(),
-            current_flash_offset,
-            remaining_data.len()
-        );
+    log::error!(
+        "Could not put the device on port {} into its bootloader with any reset strategy",
+        upload_port
+    );
 
-        let mut progress = FlashProgress {
-            total: 0,
-            current: 0,
-            app_handle: app_handle.clone(),
-            board_id: BoardId(upload_port.clone()),
-        };
+    Err(format!(
+        "Could not put the device on port {} into its bootloader. Hold the BOOT button, press and release RESET (or plug the board in while holding BOOT), release BOOT and try again.",
+        upload_port
+    ))
+}
 
-        let is_data_remaining = remaining_data.len() > 0;
+/// Connects at the configured baud rate, falling back to 115200 if the device can't keep up
+async fn connect_esp32_flasher_with_reset(
+    upload_port: &String,
+    connection_config: &EspConnectionConfig,
+    reset_strategy: &EspResetStrategy,
+) -> Result<(espflash::flasher::Flasher, u32), String> {
+    let mut baud_rate = connection_config.baud_rate;
+
+    loop {
+        let mut serial_interface = init_esp32_serial_port(upload_port, reset_strategy).await?;
+        let usb_port_info = get_serial_port_info(upload_port).await?;
+
+        match reset_strategy
+            .reset_to_bootloader(&mut serial_interface)
+            .await
+        {
+            Ok(_) => (),
+            Err(e) => {
+                log::error!(
+                    "Error while resetting port {} with strategy {:?}: {}",
+                    upload_port,
+                    reset_strategy,
+                    e
+                );
+
+                return Err(format!(
+                    "Error while resetting port {} with strategy {:?}: {}",
+                    upload_port, reset_strategy, e
+                ));
+            }
+        };
 
         log::info!(
-            "Data remaining: {}, Reboot on complete: {}",
-            is_data_remaining,
-            reboot
+            "Connecting to port {} at {} baud with reset strategy {:?}...",
+            upload_port,
+            baud_rate,
+            reset_strategy
         );
 
-        match flasher.write_bin_to_flash(
-            current_flash_offset,
-            data_chunk,
-            Some(&mut progress),
-            !is_data_remaining && reboot,
+        match espflash::flasher::Flasher::connect(
+            serial_interface,
+            usb_port_info,
+            Some(baud_rate),
+            true,
         ) {
-            Ok(_) => (),
+            Ok(flasher) => return Ok((flasher, baud_rate)),
+            Err(e) if baud_rate != FALLBACK_ESP_BAUD_RATE => {
+                log::warn!(
+                    "Error while connecting to port {} at {} baud, falling back to {} baud: {}",
+                    upload_port,
+                    baud_rate,
+                    FALLBACK_ESP_BAUD_RATE,
+                    e
+                );
+
+                baud_rate = FALLBACK_ESP_BAUD_RATE;
+            }
             Err(e) => {
-                log::error!("Error while writing data buffer to board: {}", e);
-                return Err(format!("Error while writing data buffer to board: {}", e));
+                log::error!("Error while connecting to port {}: {}", upload_port, e);
+                return Err(format!(
+                    "Error while connecting to port {}: {}",
+                    upload_port, e
+                ));
             }
         };
+    }
+}
 
-        log::debug!(
-            "Successfully flashed {} bytes with {} bytes remaining in buffer",
-            data_chunk.len(),
-            remaining_data.len()
-        );
+/// Refuses an app-only update when the device was flashed with a different partition layout
+async fn check_device_app_partition(
+    session: &mut EspFlashSession,
+    app_partition: &Partition,
+) -> Result<(), String> {
+    let temp_directory = session.temp_directory().clone();
+    let upload_port = session.upload_port().clone();
 
-        current_flash_offset += data_chunk.len() as u32;
-        binary_data_buffer = remaining_data.to_vec();
-    }
+    let device_partition_table =
+        read_partition_table_from_device(&temp_directory, session.flasher()?).await?;
+    let device_app_partition = device_partition_table.app_partition()?;
 
-    log::info!("Finished writing binary data to board");
+    match device_app_partition.check_matches(app_partition) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::error!(
+                "Device on port {} has a different app partition than the firmware bundle, a factory install is required: {}",
+                upload_port,
+                e
+            );
 
-    Ok(())
+            Err(format!(
+                "Device on port {} has a different app partition than the firmware bundle, a factory install is required: {}",
+                upload_port, e
+            ))
+        }
+    }
 }
 
 async fn init_esp32_serial_port(
     upload_port: &String,
+    reset_strategy: &EspResetStrategy,
 ) -> Result<espflash::interface::Interface, String> {
-    let dtr = Some(1);
-    let rts = Some(0);
+    let (dtr, rts) = reset_strategy.get_reset_pins();
 
     let serial_port_info = get_port_by_name(upload_port)?;
 
@@ -389,128 +614,241 @@ async fn get_serial_port_info(upload_port: &String) -> Result<serialport::UsbPor
     Ok(port_info)
 }
 
+async fn read_binary_file(binary_file_path: &PathBuf) -> Result<Vec<u8>, String> {
+    match tokio::fs::read(binary_file_path).await {
+        Ok(data) => Ok(data),
+        Err(e) => {
+            log::error!(
+                "Error while reading binary file at {}: {}",
+                binary_file_path.display(),
+                e.to_string()
+            );
+
+            Err(format!(
+                "Error while reading binary file at {}: {}",
+                binary_file_path.display(),
+                e
+            ))
+        }
+    }
+}
+
 async fn flash_esp32(
-    app_handle: tauri::AppHandle,
-    temp_firmware_file_path: PathBuf,
-    temp_ble_ota_file_path: PathBuf,
-    temp_littlefs_file_path: PathBuf,
+    files: &FlashFiles,
     upload_port: String,
-) -> Result<(), String> {
-    flash_esp_binary(
-        app_handle.clone(),
-        upload_port.clone(),
-        0x0000_0000,
-        temp_firmware_file_path,
-        false,
-    )
-    .await?;
+    board: &Board,
+    options: &FlashOptions,
+    progress: &mut JobProgress,
+) -> Result<EspFlashReport, String> {
+    let esp_target = get_esp_target(&board.architecture)?;
+
+    // The factory image is written at 0x0 and embeds the bootloader at the chip's bootloader
+    // offset and the partition table at 0x8000
+    let firmware_binary = read_binary_file(&files.firmware_file_path).await?;
+    check_esp_image(
+        &firmware_binary,
+        esp_target.bootloader_offset as usize,
+        "Firmware image",
+        &esp_target,
+    )?;
+
+    let partition_table = parse_partition_table_from_image(&firmware_binary)?;
+    let required_flash_size = partition_table.required_flash_size();
+
+    let app_partition = partition_table.app_partition()?;
+
+    if let FlashMode::Update = options.flash_mode {
+        // Update only rewrites the app partition, leaving config and node database intact
+        let update_binary = read_binary_file(&files.update_file_path).await?;
+        check_esp_image(&update_binary, 0, "Update image", &esp_target)?;
+        app_partition.check_fits(app_partition.offset, update_binary.len())?;
+
+        let mut session = EspFlashSession::connect(
+            files.temp_directory.clone(),
+            &upload_port,
+            board,
+            required_flash_size,
+            &options.connection_config,
+            progress,
+        )
+        .await?;
 
-    log::info!("Successfully flashed firmware binary at 0x0000_0000");
+        // The update binary is only valid for the layout the bundle was built with
+        check_device_app_partition(&mut session, app_partition).await?;
 
-    flash_esp_binary(
-        app_handle.clone(),
-        upload_port.clone(),
-        0x0026_0000,
-        temp_ble_ota_file_path,
-        false,
-    )
-    .await?;
+        let backup_metadata = match &options.backup_directory {
+            Some(backup_directory) => {
+                Some(backup_esp32_session(&mut session, backup_directory, board, progress).await?)
+            }
+            None => None,
+        };
 
-    log::info!("Successfully flashed BLE OTA binary at 0x0026_0000");
+        session
+            .write_images(
+                &[EspImage {
+                    name: "app update".to_string(),
+                    offset: app_partition.offset,
+                    data: &update_binary,
+                }],
+                options.verify,
+                progress,
+            )
+            .await?;
+
+        let mut flash_report = session.reset(progress)?;
+        flash_report.backup = backup_metadata;
+
+        return Ok(flash_report);
+    }
 
-    flash_esp_binary(
-        app_handle,
-        upload_port,
-        0x0030_0000,
-        temp_littlefs_file_path,
-        true,
-    )
-    .await?;
+    let littlefs_binary = read_binary_file(&files.littlefs_file_path).await?;
+    let filesystem_partition = partition_table.filesystem_partition()?;
 
-    log::info!("Successfully flashed LittleFS binary at 0x0030_0000");
+    // Factory image spans the bootloader, partition table and app partition
+    if firmware_binary.len() as u64 > app_partition.end() as u64 {
+        log::error!(
+            "Firmware image of {} bytes does not fit in app partition \"{}\" ending at 0x{:08x}",
+            firmware_binary.len(),
+            app_partition.label,
+            app_partition.end()
+        );
 
-    Ok(())
-}
+        return Err(format!(
+            "Firmware image of {} bytes does not fit in app partition \"{}\" ending at 0x{:08x}",
+            firmware_binary.len(),
+            app_partition.label,
+            app_partition.end()
+        ));
+    }
 
-async fn flash_nrf(
-    firmware_file_name: String,
-    firmware_file_path: PathBuf,
-    upload_dir: String,
-) -> Result<(), String> {
-    // Open temporary firmware file
+    filesystem_partition.check_fits(filesystem_partition.offset, littlefs_binary.len())?;
 
-    let firmware_file = match File::open(firmware_file_path.clone()).await {
-        Ok(firmware_file) => firmware_file,
-        Err(e) => {
-            log::error!(
-                "Error while opening firmware file at {}: {}",
-                firmware_file_path.display(),
-                e.to_string()
-            );
+    // Chips without a published BLE OTA binary leave the OTA partition erased
+    let ble_ota_image = match &files.ble_ota_file_path {
+        Some(ble_ota_file_path) => {
+            let ble_ota_binary = read_binary_file(ble_ota_file_path).await?;
+            let ota_partition = partition_table.ota_partition()?;
+            ota_partition.check_fits(ota_partition.offset, ble_ota_binary.len())?;
 
-            return Err(format!(
-                "Error while opening firmware file at {}: {}",
-                firmware_file_path.display(),
-                e
-            ));
+            Some((ota_partition.offset, ble_ota_binary))
         }
+        None => None,
     };
 
-    log::info!("Opened firmware file at {}", firmware_file_path.display());
-
-    // Create output file
-
-    let output_file_path = Path::new(&upload_dir).join(firmware_file_name);
+    let mut images = vec![EspImage {
+        name: "firmware".to_string(),
+        offset: 0x0000_0000,
+        data: &firmware_binary,
+    }];
+
+    if let Some((ota_offset, ble_ota_binary)) = &ble_ota_image {
+        images.push(EspImage {
+            name: "BLE OTA".to_string(),
+            offset: *ota_offset,
+            data: ble_ota_binary,
+        });
+    }
 
-    log::info!("Output file path: {}", output_file_path.display());
+    images.push(EspImage {
+        name: "LittleFS".to_string(),
+        offset: filesystem_partition.offset,
+        data: &littlefs_binary,
+    });
+
+    // One connection for the backup, erase and every image, with a single reset at the end.
+    // The chip is validated on connect, so nothing is touched on a mismatch.
+    let mut session = EspFlashSession::connect(
+        files.temp_directory.clone(),
+        &upload_port,
+        board,
+        required_flash_size,
+        &options.connection_config,
+        progress,
+    )
+    .await?;
 
-    let output_file = match File::create(output_file_path.clone()).await {
-        Ok(output_file) => output_file,
-        Err(e) => {
-            log::error!(
-                "Error while creating output file at {}: {}",
-                output_file_path.display(),
-                e.to_string()
-            );
+    // Erasing a secured device before refusing the writes would leave it blank
+    check_plaintext_writable(&session.chip_info().security)?;
 
-            return Err(format!(
-                "Error while creating output file at {}: {}",
-                output_file_path.display(),
-                e
-            ));
+    let backup_metadata = match &options.backup_directory {
+        Some(backup_directory) => {
+            Some(backup_esp32_session(&mut session, backup_directory, board, progress).await?)
         }
+        None => None,
     };
 
-    log::info!("Opened output file at {}", output_file_path.display());
-
-    // Write contents of firmware file to output file
+    progress.check_cancelled()?;
 
-    let mut firmware_file_reader = tokio::io::BufReader::new(firmware_file);
+    erase_esp32_session(&mut session, EraseTarget::FullChip, progress).await?;
 
-    log::info!("Created firmware file reader");
+    session
+        .write_images(&images, options.verify, progress)
+        .await?;
 
-    let mut output_file_writer = tokio::io::BufWriter::new(output_file);
+    let mut flash_report = session.reset(progress)?;
+    flash_report.backup = backup_metadata;
 
-    log::info!("Created output file writer");
+    Ok(flash_report)
+}
 
-    match tokio::io::copy(&mut firmware_file_reader, &mut output_file_writer).await {
-        Ok(_) => (),
-        Err(e) => {
-            log::error!(
-                "Error while copying firmware file to output file at {}: {}",
-                output_file_path.display(),
-                e.to_string()
-            );
+/// Copies a UF2 image to an nRF52 board's bootloader drive and waits for the firmware to come
+/// back, returning the firmware's serial port and the backup of CURRENT.UF2 taken beforehand, if
+/// any
+///
+/// `ignore_bootloader_check` skips the bootloader compatibility check, for bootloaders whose
+/// INFO_UF2.TXT can't be read or that the user has already checked by hand.
+async fn flash_nrf(
+    files: &FlashFiles,
+    upload_port: String,
+    board: &Board,
+    options: &FlashOptions,
+    progress: &mut JobProgress,
+) -> Result<(String, Option<BackupMetadata>), String> {
+    // Read before touching, since the firmware's port disappears into the bootloader
+    let firmware_usb_ids = get_firmware_usb_ids(&upload_port, board)?;
+
+    let mut uf2_drive_path = get_uf2_drive(&upload_port, progress).await?;
+
+    // Old bootloaders accept new images and then boot loop, so they are caught up front
+    if options.ignore_bootloader_check {
+        log::warn!(
+            "Skipping bootloader compatibility check for UF2 drive at {}",
+            uf2_drive_path.display()
+        );
+    } else {
+        let bootloader_info = read_uf2_bootloader_info(&uf2_drive_path)?;
+        check_nrf_bootloader_compatible(&get_nrf_bootloader_status(&bootloader_info))?;
+    }
 
-            return Err(format!(
-                "Error while copying firmware file to output file at {}: {}",
-                output_file_path.display(),
-                e
-            ));
+    // Taken before erasing, which would leave nothing in CURRENT.UF2 worth keeping
+    let backup_metadata = match &options.backup_directory {
+        Some(backup_directory) => {
+            Some(backup_uf2_device(backup_directory, &uf2_drive_path, board, progress).await?)
         }
+        None => None,
     };
 
-    log::info!("Wrote firmware file to {}", output_file_path.display());
+    if let Some(erase_file_path) = &files.uf2_erase_file_path {
+        uf2_drive_path = erase_uf2_device(&uf2_drive_path, erase_file_path, progress).await?;
+    }
+
+    let bootloader_ports = list_usb_serial_ports()?;
+
+    copy_uf2_file(
+        files.firmware_file_name.clone(),
+        files.firmware_file_path.clone(),
+        uf2_drive_path.clone(),
+        progress,
+    )
+    .await?;
+
+    let firmware_port = wait_for_uf2_reboot(
+        &uf2_drive_path,
+        &firmware_usb_ids,
+        &bootloader_ports,
+        progress,
+    )
+    .await?;
 
-    Ok(())
+    Ok((firmware_port, backup_metadata))
 }
@@ -1,51 +1,13 @@
 use std::{
     io::{Cursor, Read},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
-use tauri::AppHandle;
 use tokio::{fs::File, io::AsyncWriteExt};
 use zip::ZipArchive;
 
 use crate::{api, flasher::FirmwareVersion};
 
-pub async fn create_or_locate_firmware_directory(
-    app_handle: &AppHandle,
-) -> Result<PathBuf, String> {
-    let path_resolver = app_handle.path_resolver();
-
-    let app_data_dir = match path_resolver.app_data_dir() {
-        Some(app_data_dir) => app_data_dir,
-        None => {
-            log::error!("Error while resolving app data directory");
-            return Err(format!("Error while resolving app data directory"));
-        }
-    };
-
-    log::info!("Found app data dir at location {}", app_data_dir.display());
-    let firmware_directory = app_data_dir.join("firmware");
-
-    if !firmware_directory.exists() {
-        let new_firmware_directory = app_data_dir.join(Path::new("firmware"));
-
-        log::info!(
-            "Creating firmware directory at {}",
-            new_firmware_directory.display()
-        );
-
-        match tokio::fs::create_dir_all(new_firmware_directory).await {
-            Ok(_) => (),
-            Err(e) => {
-                log::error!("Error while creating firmware directory: {}", e.to_string());
-
-                return Err(format!("Error while creating firmware directory: {}", e));
-            }
-        };
-    }
-
-    Ok(firmware_directory)
-}
-
 pub fn get_firmware_file_name(
     board: &api::boards::Board,
     parsed_firmware_version: &FirmwareVersion,
@@ -66,6 +28,25 @@ pub fn get_firmware_file_name(
     Ok(firmware_file_name)
 }
 
+/// App-only image used by ESP32 update installs, written to the app partition
+pub fn get_update_firmware_file_name(
+    board: &api::boards::Board,
+    parsed_firmware_version: &FirmwareVersion,
+) -> String {
+    let update_file_name = format!(
+        "firmware-{}-{}.{}.{}.{}-update.bin",
+        board.platformio_target.to_lowercase(),
+        parsed_firmware_version.major_version,
+        parsed_firmware_version.minor_version,
+        parsed_firmware_version.patch_version,
+        parsed_firmware_version.version_hash
+    );
+
+    log::info!("Built update firmware file name: {}", update_file_name);
+
+    update_file_name
+}
+
 pub async fn create_archive_from_bytes(
     firmware_zip_bundle_bytes: bytes::Bytes,
 ) -> Result<ZipArchive<Cursor<bytes::Bytes>>, String> {
@@ -125,6 +106,41 @@ pub async fn extract_binary_from_archive(
     Ok(contents)
 }
 
+/// nrfutil DFU package for nRF52 serial DFU, holding the same app as the UF2 image
+pub fn get_nrf_dfu_package_file_name(
+    board: &api::boards::Board,
+    parsed_firmware_version: &FirmwareVersion,
+) -> String {
+    let dfu_package_file_name = format!(
+        "firmware-{}-{}.{}.{}.{}-ota.zip",
+        board.platformio_target.to_lowercase(),
+        parsed_firmware_version.major_version,
+        parsed_firmware_version.minor_version,
+        parsed_firmware_version.patch_version,
+        parsed_firmware_version.version_hash
+    );
+
+    log::info!("Built DFU package file name: {}", dfu_package_file_name);
+
+    dfu_package_file_name
+}
+
+/// Erase image for UF2 targets, published in the release bundle and bundled in the erase
+/// resource directory as a fallback
+pub fn get_uf2_erase_file_name(board: &api::boards::Board) -> Result<String, String> {
+    if board.architecture.contains("nrf") {
+        Ok("Meshtastic_nRF52_factory_erase_v2.uf2".to_string())
+    } else if board.architecture.contains("rp2040") {
+        Ok("flash_nuke.uf2".to_string())
+    } else {
+        log::error!("No erase image for architecture {}", board.architecture);
+        Err(format!(
+            "No erase image for architecture {}",
+            board.architecture
+        ))
+    }
+}
+
 fn get_esp_firmware_name(slug: &String, firmware_version: &FirmwareVersion) -> String {
     format!(
         "firmware-{}-{}.{}.{}.{}.bin",
@@ -158,22 +174,41 @@ fn get_pico_firmware_name(slug: &String, firmware_version: &FirmwareVersion) ->
     )
 }
 
-// ? Is it a problem to write into the general temp directory?
-pub fn get_temp_file_path(
-    app_handle: &AppHandle,
-    firmware_file_name: String,
+/// Resolves a file bundled under `resource_directory`, refusing absolute paths and anything
+/// else that could point outside it
+pub fn get_resource_file_path(
+    resource_directory: &Path,
+    resource_file_name: &str,
 ) -> Result<PathBuf, String> {
-    let path_resolver = app_handle.path_resolver();
+    let is_relative = Path::new(resource_file_name)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+
+    if resource_file_name.is_empty() || !is_relative {
+        log::error!("Invalid resource file name: {}", resource_file_name);
+        return Err(format!(
+            "Invalid resource file name: {}",
+            resource_file_name
+        ));
+    }
 
-    let temp_file_path = match path_resolver.app_cache_dir() {
-        Some(temp_dir) => temp_dir.join(firmware_file_name.clone()),
-        None => {
-            log::error!("Error while resolving temp directory");
-            return Err(format!("Error while resolving temp directory"));
-        }
-    };
+    let resource_file_path = resource_directory.join(resource_file_name);
+
+    if !resource_file_path.is_file() {
+        log::error!(
+            "Resource {} is not bundled in {}",
+            resource_file_name,
+            resource_directory.display()
+        );
+
+        return Err(format!(
+            "Resource {} is not bundled in {}",
+            resource_file_name,
+            resource_directory.display()
+        ));
+    }
 
-    Ok(temp_file_path)
+    Ok(resource_file_path)
 }
 
 pub async fn write_binary_to_temp_file(
@@ -218,3 +253,76 @@ pub async fn write_binary_to_temp_file(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::build_boards;
+
+    fn get_bootloader_resource_directory() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/bootloaders")
+    }
+
+    fn get_erase_resource_directory() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/erase")
+    }
+
+    #[test]
+    fn resolves_bundled_resources() {
+        let resource_directory = get_bootloader_resource_directory();
+
+        assert_eq!(
+            get_resource_file_path(&resource_directory, "README.md"),
+            Ok(resource_directory.join("README.md"))
+        );
+        assert!(get_resource_file_path(&resource_directory, "missing.uf2").is_err());
+    }
+
+    #[test]
+    fn refuses_paths_outside_the_resource_directory() {
+        let resource_directory = get_bootloader_resource_directory();
+
+        assert!(get_resource_file_path(&resource_directory, "../../Cargo.toml").is_err());
+        assert!(get_resource_file_path(&resource_directory, "./README.md").is_err());
+        assert!(get_resource_file_path(&resource_directory, "").is_err());
+
+        let absolute_path = resource_directory.join("README.md");
+        assert!(
+            get_resource_file_path(&resource_directory, &absolute_path.to_string_lossy()).is_err()
+        );
+    }
+
+    #[test]
+    fn picks_erase_images_by_architecture() {
+        let erase_file_names: Vec<Result<String, String>> =
+            build_boards().iter().map(get_uf2_erase_file_name).collect();
+
+        assert_eq!(
+            erase_file_names,
+            vec![
+                Ok("Meshtastic_nRF52_factory_erase_v2.uf2".to_string()),
+                Ok("Meshtastic_nRF52_factory_erase_v2.uf2".to_string()),
+                Ok("flash_nuke.uf2".to_string()),
+                Err("No erase image for architecture esp32".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs the erase images listed in resources/erase/README.md"]
+    fn resolves_bundled_erase_images() {
+        let erase_directory = get_erase_resource_directory();
+
+        for board in build_boards()
+            .iter()
+            .filter(|board| !board.architecture.contains("esp"))
+        {
+            let erase_file_name = get_uf2_erase_file_name(board).unwrap();
+
+            assert_eq!(
+                get_resource_file_path(&erase_directory, &erase_file_name),
+                Ok(erase_directory.join(&erase_file_name))
+            );
+        }
+    }
+}
@@ -0,0 +1,249 @@
+// SPI flash JEDEC ID read over the SPI0/SPI1 controller registers, as esptool does in
+// `run_spiflash_command`: https://github.com/espressif/esptool/blob/master/esptool/loader.py
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use espflash::{flasher::Flasher, targets::Chip};
+
+use crate::api::boards::Board;
+use crate::chip::EspChipInfo;
+use crate::flasher::EspConnectionConfig;
+use crate::progress::JobProgress;
+use crate::session::EspFlashSession;
+
+const SPI_FLASH_RDID: u32 = 0x9F;
+const SPI_FLASH_RDID_BITS: u32 = 24; // Manufacturer ID, then two device ID bytes
+
+const SPI_CMD_USR: u32 = 1 << 18;
+const SPI_USR_COMMAND: u32 = 1 << 31;
+const SPI_USR_MISO: u32 = 1 << 28;
+const SPI_USR2_COMMAND_LEN_SHIFT: u32 = 28;
+const SPI_USR_COMMAND_BITS: u32 = 8;
+const SPI_CMD_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Register offsets of the SPI flash controller, which moved after the original ESP32
+struct SpiRegisters {
+    base: u32,
+    usr_offset: u32,
+    usr2_offset: u32,
+    miso_dlen_offset: u32,
+    w0_offset: u32,
+}
+
+fn get_spi_registers(chip: &Chip) -> Result<SpiRegisters, String> {
+    let (base, usr_offset, usr2_offset, miso_dlen_offset, w0_offset) = match chip {
+        Chip::Esp32 => (0x3FF4_2000, 0x1C, 0x24, 0x2C, 0x80),
+        Chip::Esp32s2 => (0x3F40_2000, 0x18, 0x20, 0x28, 0x58),
+        Chip::Esp32s3 | Chip::Esp32c3 => (0x6000_2000, 0x18, 0x20, 0x28, 0x58),
+        Chip::Esp32c6 | Chip::Esp32h2 => (0x6000_3000, 0x18, 0x20, 0x28, 0x58),
+        _ => {
+            log::error!("SPI flash register layout unknown for chip {}", chip);
+            return Err(format!(
+                "SPI flash register layout unknown for chip {}",
+                chip
+            ));
+        }
+    };
+
+    Ok(SpiRegisters {
+        base,
+        usr_offset,
+        usr2_offset,
+        miso_dlen_offset,
+        w0_offset,
+    })
+}
+
+/// Hardware identifiers used to track a device in inventory
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EspDeviceIdentity {
+    pub chip: String,
+    pub chip_revision: Option<String>,
+    pub base_mac_address: String,
+    pub node_id: String, // Meshtastic node ID, e.g. !a1b2c3d4
+    pub flash_manufacturer_id: u8,
+    pub flash_device_id: u16,
+    pub flash_size: String,
+    pub flash_size_bytes: u32,
+}
+
+/// Meshtastic derives the node number from the last four bytes of the base MAC
+pub fn get_node_id(mac_address: &String) -> Result<String, String> {
+    let mac_bytes = mac_address
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<Result<Vec<u8>, _>>();
+
+    match mac_bytes {
+        Ok(mac_bytes) if mac_bytes.len() == 6 => Ok(format!(
+            "!{:02x}{:02x}{:02x}{:02x}",
+            mac_bytes[2], mac_bytes[3], mac_bytes[4], mac_bytes[5]
+        )),
+        _ => {
+            log::error!("Invalid MAC address: {}", mac_address);
+            Err(format!("Invalid MAC address: {}", mac_address))
+        }
+    }
+}
+
+fn write_spi_register(flasher: &mut Flasher, address: u32, value: u32) -> Result<(), String> {
+    match flasher.connection().write_reg(address, value, None) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::error!("Error while writing SPI register 0x{:08x}: {}", address, e);
+            Err(format!(
+                "Error while writing SPI register 0x{:08x}: {}",
+                address, e
+            ))
+        }
+    }
+}
+
+fn read_spi_register(flasher: &mut Flasher, address: u32) -> Result<u32, String> {
+    match flasher.connection().read_reg(address) {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            log::error!("Error while reading SPI register 0x{:08x}: {}", address, e);
+            Err(format!(
+                "Error while reading SPI register 0x{:08x}: {}",
+                address, e
+            ))
+        }
+    }
+}
+
+/// Sends RDID to the attached flash and returns the raw 24-bit JEDEC ID
+fn read_flash_jedec_id(flasher: &mut Flasher, chip: &Chip) -> Result<u32, String> {
+    let registers = get_spi_registers(chip)?;
+    let usr_register = registers.base + registers.usr_offset;
+    let usr2_register = registers.base + registers.usr2_offset;
+
+    // The loader uses the same controller for flash access, so its setup is restored afterwards
+    let old_usr = read_spi_register(flasher, usr_register)?;
+    let old_usr2 = read_spi_register(flasher, usr2_register)?;
+
+    let jedec_id = run_flash_rdid_command(flasher, &registers);
+
+    // Restored even when the command failed, so later flash access isn't left broken
+    let restore_result = write_spi_register(flasher, usr_register, old_usr)
+        .and_then(|_| write_spi_register(flasher, usr2_register, old_usr2));
+
+    let jedec_id = jedec_id?;
+    restore_result?;
+
+    Ok(jedec_id)
+}
+
+fn run_flash_rdid_command(flasher: &mut Flasher, registers: &SpiRegisters) -> Result<u32, String> {
+    let cmd_register = registers.base;
+    let usr_register = registers.base + registers.usr_offset;
+    let usr2_register = registers.base + registers.usr2_offset;
+    let w0_register = registers.base + registers.w0_offset;
+
+    write_spi_register(
+        flasher,
+        registers.base + registers.miso_dlen_offset,
+        SPI_FLASH_RDID_BITS - 1,
+    )?;
+    write_spi_register(flasher, usr_register, SPI_USR_COMMAND | SPI_USR_MISO)?;
+    write_spi_register(
+        flasher,
+        usr2_register,
+        ((SPI_USR_COMMAND_BITS - 1) << SPI_USR2_COMMAND_LEN_SHIFT) | SPI_FLASH_RDID,
+    )?;
+    write_spi_register(flasher, w0_register, 0)?;
+    write_spi_register(flasher, cmd_register, SPI_CMD_USR)?;
+
+    let deadline = Instant::now() + SPI_CMD_TIMEOUT;
+
+    while read_spi_register(flasher, cmd_register)? & SPI_CMD_USR != 0 {
+        if Instant::now() >= deadline {
+            log::error!(
+                "SPI flash did not complete the read ID command within {:?}",
+                SPI_CMD_TIMEOUT
+            );
+
+            return Err(format!(
+                "SPI flash did not complete the read ID command within {:?}",
+                SPI_CMD_TIMEOUT
+            ));
+        }
+    }
+
+    Ok(read_spi_register(flasher, w0_register)? & 0x00FF_FFFF)
+}
+
+/// Reads the identity of a chip that has already been detected over `flasher`
+pub fn read_esp_identity(
+    flasher: &mut Flasher,
+    chip: &Chip,
+    chip_info: &EspChipInfo,
+) -> Result<EspDeviceIdentity, String> {
+    let jedec_id = read_flash_jedec_id(flasher, chip)?;
+
+    let identity = EspDeviceIdentity {
+        chip: chip_info.chip.clone(),
+        chip_revision: chip_info.revision.clone(),
+        base_mac_address: chip_info.mac_address.clone(),
+        node_id: get_node_id(&chip_info.mac_address)?,
+        flash_manufacturer_id: (jedec_id & 0xFF) as u8,
+        // Memory type then capacity, in the order datasheets print the device ID
+        flash_device_id: ((((jedec_id >> 8) & 0xFF) << 8) | ((jedec_id >> 16) & 0xFF)) as u16,
+        flash_size: chip_info.flash_size.clone(),
+        flash_size_bytes: chip_info.flash_size_bytes,
+    };
+
+    log::info!("Read device identity: {:?}", identity);
+
+    Ok(identity)
+}
+
+/// Connects to the device only to read its identity, then reboots it
+pub async fn read_esp32_identity(
+    temp_directory: PathBuf,
+    upload_port: String,
+    board: &Board,
+    connection_config: &EspConnectionConfig,
+    progress: &mut JobProgress,
+) -> Result<EspDeviceIdentity, String> {
+    let session = EspFlashSession::connect(
+        temp_directory,
+        &upload_port,
+        board,
+        0,
+        connection_config,
+        progress,
+    )
+    .await?;
+
+    let flash_report = session.reset(progress)?;
+
+    Ok(flash_report.identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_node_id_from_last_four_mac_bytes() {
+        assert_eq!(
+            get_node_id(&"24:0a:c4:a1:b2:c3".to_string()),
+            Ok("!c4a1b2c3".to_string())
+        );
+        assert_eq!(
+            get_node_id(&"F4:12:FA:0B:0C:0D".to_string()),
+            Ok("!fa0b0c0d".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_mac_addresses() {
+        assert!(get_node_id(&"24:0a:c4:a1:b2".to_string()).is_err());
+        assert!(get_node_id(&"24:0a:c4:a1:b2:c3:d4".to_string()).is_err());
+        assert!(get_node_id(&"24:0a:c4:a1:b2:zz".to_string()).is_err());
+        assert!(get_node_id(&"".to_string()).is_err());
+    }
+}
@@ -0,0 +1,20 @@
+// Flashing engine, independent of the Tauri front end in main.rs
+
+pub mod api;
+pub mod backup;
+pub mod bootloader;
+pub mod chip;
+pub mod dfu;
+pub mod erase;
+pub mod flasher;
+pub mod fs;
+pub mod identity;
+pub mod partitions;
+pub mod progress;
+pub mod rp2040;
+pub mod security;
+pub mod session;
+pub mod uf2;
+
+#[cfg(test)]
+mod test_fixtures;
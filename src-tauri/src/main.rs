@@ -5,10 +5,8 @@ use log::{debug, error, info};
 use tauri::{CustomMenuItem, Manager, Menu, MenuItem, Submenu};
 use tauri_plugin_log::LogTarget;
 
-pub mod api;
 pub mod commands;
-pub mod flasher;
-pub mod fs;
+pub mod paths;
 pub mod state;
 
 enum MenuItemId {
@@ -220,14 +218,27 @@ fn main() {
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
+            commands::backup_device,
+            commands::cancel_flash_job,
+            commands::erase_device,
             commands::fetch_firmware_releases,
             commands::fetch_supported_boards,
             commands::flash_device,
             commands::get_available_serial_ports,
+            commands::get_available_uf2_drives,
+            commands::get_device_backups,
+            commands::get_nrf_bootloader_status,
+            commands::get_rp2040_flash_plan,
             commands::quit_application,
+            commands::read_device_identity,
+            commands::restore_device,
+            commands::restore_uf2_device,
+            commands::update_nrf_bootloader,
         ])
+        .manage(state::BaudRateState::default())
         .manage(state::BoardsState::default())
         .manage(state::FirmwareReleasesState::default())
+        .manage(state::FlashJobsState::default())
         .plugin(
             tauri_plugin_log::Builder::default()
                 .targets([LogTarget::LogDir, LogTarget::Stdout, LogTarget::Webview])
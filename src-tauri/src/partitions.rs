@@ -0,0 +1,458 @@
+// Parser for the binary ESP-IDF partition table format
+// https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-guides/partition-tables.html
+
+use std::path::Path;
+
+use espflash::flasher::Flasher;
+
+/// Offset of the partition table within flash (and within a merged factory image)
+pub const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+pub const PARTITION_TABLE_MAX_LENGTH: usize = 0xC00;
+
+pub(crate) const PARTITION_ENTRY_LENGTH: usize = 32;
+pub(crate) const PARTITION_ENTRY_MAGIC: [u8; 2] = [0xAA, 0x50];
+pub(crate) const PARTITION_MD5_MAGIC: [u8; 2] = [0xEB, 0xEB];
+
+pub const PARTITION_TYPE_APP: u8 = 0x00;
+pub const PARTITION_TYPE_DATA: u8 = 0x01;
+
+pub const APP_SUBTYPE_FACTORY: u8 = 0x00;
+pub const APP_SUBTYPE_OTA_0: u8 = 0x10;
+pub const APP_SUBTYPE_OTA_1: u8 = 0x11;
+
+pub const DATA_SUBTYPE_NVS: u8 = 0x02;
+pub const DATA_SUBTYPE_SPIFFS: u8 = 0x82;
+pub const DATA_SUBTYPE_LITTLEFS: u8 = 0x83;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Partition {
+    pub label: String,
+    pub partition_type: u8,
+    pub subtype: u8,
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl Partition {
+    /// End of the partition, which `parse_partition_table` guarantees fits in 32 bits
+    pub fn end(&self) -> u32 {
+        self.offset + self.size
+    }
+
+    /// Checks that an image of `image_length` bytes written at `offset` stays within this partition
+    pub fn check_fits(&self, offset: u32, image_length: usize) -> Result<(), String> {
+        let image_end = offset as u64 + image_length as u64;
+
+        if offset < self.offset || image_end > self.end() as u64 {
+            log::error!(
+                "Image of {} bytes at 0x{:08x} does not fit in partition \"{}\" (0x{:08x}..0x{:08x})",
+                image_length,
+                offset,
+                self.label,
+                self.offset,
+                self.end()
+            );
+
+            return Err(format!(
+                "Image of {} bytes at 0x{:08x} does not fit in partition \"{}\" (0x{:08x}..0x{:08x})",
+                image_length,
+                offset,
+                self.label,
+                self.offset,
+                self.end()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `other` covers exactly the same flash region as this partition
+    pub fn check_matches(&self, other: &Partition) -> Result<(), String> {
+        if self.offset != other.offset || self.size != other.size {
+            log::error!(
+                "Partition \"{}\" (0x{:08x}..0x{:08x}) does not match partition \"{}\" (0x{:08x}..0x{:08x})",
+                self.label,
+                self.offset,
+                self.end(),
+                other.label,
+                other.offset,
+                other.end()
+            );
+
+            return Err(format!(
+                "Partition \"{}\" (0x{:08x}..0x{:08x}) does not match partition \"{}\" (0x{:08x}..0x{:08x})",
+                self.label,
+                self.offset,
+                self.end(),
+                other.label,
+                other.offset,
+                other.end()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PartitionTable {
+    pub partitions: Vec<Partition>,
+}
+
+impl PartitionTable {
+    /// Partition the device boots the main firmware from (factory, falling back to ota_0)
+    pub fn app_partition(&self) -> Result<&Partition, String> {
+        self.find(PARTITION_TYPE_APP, APP_SUBTYPE_FACTORY)
+            .or_else(|| self.find(PARTITION_TYPE_APP, APP_SUBTYPE_OTA_0))
+            .ok_or_else(|| {
+                log::error!("No app partition found in partition table");
+                "No app partition found in partition table".to_string()
+            })
+    }
+
+    /// Partition holding the BLE OTA companion firmware (`flashApp` in Meshtastic tables)
+    pub fn ota_partition(&self) -> Result<&Partition, String> {
+        self.find(PARTITION_TYPE_APP, APP_SUBTYPE_OTA_1)
+            .ok_or_else(|| {
+                log::error!("No OTA partition found in partition table");
+                "No OTA partition found in partition table".to_string()
+            })
+    }
+
+    pub fn filesystem_partition(&self) -> Result<&Partition, String> {
+        self.find(PARTITION_TYPE_DATA, DATA_SUBTYPE_SPIFFS)
+            .or_else(|| self.find(PARTITION_TYPE_DATA, DATA_SUBTYPE_LITTLEFS))
+            .ok_or_else(|| {
+                log::error!("No filesystem partition found in partition table");
+                "No filesystem partition found in partition table".to_string()
+            })
+    }
+
+    /// Partition holding stored preferences and Bluetooth pairings
+    pub fn nvs_partition(&self) -> Result<&Partition, String> {
+        self.find(PARTITION_TYPE_DATA, DATA_SUBTYPE_NVS)
+            .ok_or_else(|| {
+                log::error!("No NVS partition found in partition table");
+                "No NVS partition found in partition table".to_string()
+            })
+    }
+
+    /// Minimum flash size needed to hold every partition in the table
+    pub fn required_flash_size(&self) -> u32 {
+        self.partitions.iter().map(|p| p.end()).max().unwrap_or(0)
+    }
+
+    fn find(&self, partition_type: u8, subtype: u8) -> Option<&Partition> {
+        self.partitions
+            .iter()
+            .find(|p| p.partition_type == partition_type && p.subtype == subtype)
+    }
+}
+
+/// Parses a binary partition table, stopping at the MD5 entry or the first erased entry
+pub fn parse_partition_table(table_bytes: &[u8]) -> Result<PartitionTable, String> {
+    let mut partitions: Vec<Partition> = Vec::new();
+
+    for entry in table_bytes
+        .chunks_exact(PARTITION_ENTRY_LENGTH)
+        .take(PARTITION_TABLE_MAX_LENGTH / PARTITION_ENTRY_LENGTH)
+    {
+        if entry[0..2] == PARTITION_MD5_MAGIC || entry.iter().all(|b| *b == 0xFF) {
+            break;
+        }
+
+        if entry[0..2] != PARTITION_ENTRY_MAGIC {
+            log::error!(
+                "Invalid partition table entry magic {:02x}{:02x}",
+                entry[0],
+                entry[1]
+            );
+
+            return Err(format!(
+                "Invalid partition table entry magic {:02x}{:02x}",
+                entry[0], entry[1]
+            ));
+        }
+
+        let label_bytes = &entry[12..28];
+        let label_length = label_bytes
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(label_bytes.len());
+
+        let partition = Partition {
+            label: String::from_utf8_lossy(&label_bytes[..label_length]).to_string(),
+            partition_type: entry[2],
+            subtype: entry[3],
+            offset: u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+            size: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]),
+        };
+
+        if partition.offset.checked_add(partition.size).is_none() {
+            log::error!(
+                "Partition \"{}\" at 0x{:08x} with size 0x{:08x} extends past 4GB",
+                partition.label,
+                partition.offset,
+                partition.size
+            );
+
+            return Err(format!(
+                "Partition \"{}\" at 0x{:08x} with size 0x{:08x} extends past 4GB",
+                partition.label, partition.offset, partition.size
+            ));
+        }
+
+        if let Some(overlapping) = partitions
+            .iter()
+            .find(|p| partition.offset < p.end() && p.offset < partition.end())
+        {
+            log::error!(
+                "Partition \"{}\" (0x{:08x}..0x{:08x}) overlaps partition \"{}\" (0x{:08x}..0x{:08x})",
+                partition.label,
+                partition.offset,
+                partition.end(),
+                overlapping.label,
+                overlapping.offset,
+                overlapping.end()
+            );
+
+            return Err(format!(
+                "Partition \"{}\" (0x{:08x}..0x{:08x}) overlaps partition \"{}\" (0x{:08x}..0x{:08x})",
+                partition.label,
+                partition.offset,
+                partition.end(),
+                overlapping.label,
+                overlapping.offset,
+                overlapping.end()
+            ));
+        }
+
+        partitions.push(partition);
+    }
+
+    if partitions.is_empty() {
+        log::error!("Partition table contains no partitions");
+        return Err("Partition table contains no partitions".to_string());
+    }
+
+    log::debug!("Parsed partition table: {:?}", partitions);
+
+    Ok(PartitionTable { partitions })
+}
+
+/// Reads the partition table embedded in a merged factory image written at 0x0
+pub fn parse_partition_table_from_image(image: &[u8]) -> Result<PartitionTable, String> {
+    let table_start = PARTITION_TABLE_OFFSET as usize;
+
+    if image.len() < table_start + PARTITION_ENTRY_LENGTH {
+        log::error!(
+            "Firmware image of {} bytes is too small to contain a partition table",
+            image.len()
+        );
+
+        return Err(format!(
+            "Firmware image of {} bytes is too small to contain a partition table",
+            image.len()
+        ));
+    }
+
+    let table_end = image.len().min(table_start + PARTITION_TABLE_MAX_LENGTH);
+
+    parse_partition_table(&image[table_start..table_end])
+}
+
+/// Reads the partition table back from a connected device's flash
+pub async fn read_partition_table_from_device(
+    temp_directory: &Path,
+    flasher: &mut Flasher,
+) -> Result<PartitionTable, String> {
+    let temp_table_file_path = temp_directory.join("partitions-readback.bin");
+
+    match flasher.read_flash(
+        PARTITION_TABLE_OFFSET,
+        PARTITION_TABLE_MAX_LENGTH as u32,
+        0x1000,
+        64,
+        temp_table_file_path.clone(),
+    ) {
+        Ok(_) => (),
+        Err(e) => {
+            log::error!("Error while reading partition table from device: {}", e);
+            return Err(format!(
+                "Error while reading partition table from device: {}",
+                e
+            ));
+        }
+    };
+
+    let table_bytes = match tokio::fs::read(&temp_table_file_path).await {
+        Ok(table_bytes) => table_bytes,
+        Err(e) => {
+            log::error!(
+                "Error while reading partition table file at {}: {}",
+                temp_table_file_path.display(),
+                e
+            );
+
+            return Err(format!(
+                "Error while reading partition table file at {}: {}",
+                temp_table_file_path.display(),
+                e
+            ));
+        }
+    };
+
+    parse_partition_table(&table_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{
+        build_meshtastic_partition_table, build_partition_entry, build_partition_md5_entry,
+    };
+
+    #[test]
+    fn parses_valid_table() {
+        let mut table_bytes = build_meshtastic_partition_table();
+        table_bytes.extend_from_slice(&[0xFF; PARTITION_ENTRY_LENGTH]);
+
+        let table = parse_partition_table(&table_bytes).unwrap();
+
+        assert_eq!(table.partitions.len(), 5);
+        assert_eq!(table.app_partition().unwrap().label, "app");
+        assert_eq!(table.ota_partition().unwrap().offset, 0x260000);
+        assert_eq!(table.filesystem_partition().unwrap().offset, 0x300000);
+        assert_eq!(table.nvs_partition().unwrap().size, 0x5000);
+        assert_eq!(table.required_flash_size(), 0x400000);
+    }
+
+    #[test]
+    fn stops_at_md5_entry() {
+        let mut table_bytes = build_meshtastic_partition_table();
+        table_bytes.extend_from_slice(&build_partition_md5_entry());
+        table_bytes.extend_from_slice(&[0x00; PARTITION_ENTRY_LENGTH]); // Not a valid entry
+
+        let table = parse_partition_table(&table_bytes).unwrap();
+
+        assert_eq!(table.partitions.len(), 5);
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut table_bytes = build_meshtastic_partition_table();
+        table_bytes[PARTITION_ENTRY_LENGTH] = 0x00;
+
+        assert!(parse_partition_table(&table_bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_empty_table() {
+        assert!(parse_partition_table(&[0xFF; PARTITION_TABLE_MAX_LENGTH]).is_err());
+    }
+
+    #[test]
+    fn rejects_overlapping_entries() {
+        let table_bytes = [
+            build_partition_entry(
+                "app",
+                PARTITION_TYPE_APP,
+                APP_SUBTYPE_FACTORY,
+                0x10000,
+                0x100000,
+            ),
+            build_partition_entry(
+                "spiffs",
+                PARTITION_TYPE_DATA,
+                DATA_SUBTYPE_SPIFFS,
+                0x100000,
+                0x10000,
+            ),
+        ]
+        .concat();
+
+        assert!(parse_partition_table(&table_bytes).is_err());
+    }
+
+    #[test]
+    fn accepts_adjacent_entries() {
+        let table_bytes = [
+            build_partition_entry(
+                "app",
+                PARTITION_TYPE_APP,
+                APP_SUBTYPE_FACTORY,
+                0x10000,
+                0x100000,
+            ),
+            build_partition_entry(
+                "spiffs",
+                PARTITION_TYPE_DATA,
+                DATA_SUBTYPE_SPIFFS,
+                0x110000,
+                0x10000,
+            ),
+        ]
+        .concat();
+
+        assert_eq!(
+            parse_partition_table(&table_bytes)
+                .unwrap()
+                .partitions
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn rejects_overflowing_entries() {
+        let table_bytes = build_partition_entry(
+            "spiffs",
+            PARTITION_TYPE_DATA,
+            DATA_SUBTYPE_SPIFFS,
+            0xFFFF_0000,
+            0x0002_0000,
+        );
+
+        assert!(parse_partition_table(&table_bytes).is_err());
+    }
+
+    #[test]
+    fn checks_images_fit_in_partition() {
+        let table = parse_partition_table(&build_meshtastic_partition_table()).unwrap();
+        let app_partition = table.app_partition().unwrap();
+
+        assert!(app_partition.check_fits(0x10000, 0x250000).is_ok());
+        assert!(app_partition.check_fits(0x10000, 0x250001).is_err());
+        assert!(app_partition.check_fits(0x0, 0x1000).is_err());
+    }
+
+    #[test]
+    fn checks_partitions_match() {
+        let table = parse_partition_table(&build_meshtastic_partition_table()).unwrap();
+        let app_partition = table.app_partition().unwrap();
+
+        let mut moved_partition = app_partition.clone();
+        moved_partition.offset = 0x20000;
+
+        let mut resized_partition = app_partition.clone();
+        resized_partition.size = 0x1E0000;
+
+        assert!(app_partition.check_matches(app_partition).is_ok());
+        assert!(app_partition.check_matches(&moved_partition).is_err());
+        assert!(app_partition.check_matches(&resized_partition).is_err());
+    }
+
+    #[test]
+    fn parses_table_from_factory_image() {
+        let mut image = vec![0xFF; PARTITION_TABLE_OFFSET as usize];
+        image.extend_from_slice(&build_meshtastic_partition_table());
+        image.extend_from_slice(&[0xFF; PARTITION_TABLE_MAX_LENGTH]);
+
+        let table = parse_partition_table_from_image(&image).unwrap();
+
+        assert_eq!(table.partitions.len(), 5);
+        assert!(parse_partition_table_from_image(&image[..0x8010]).is_err());
+    }
+}
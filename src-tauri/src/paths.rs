@@ -0,0 +1,124 @@
+// App data, cache and bundled resource locations, resolved through Tauri and handed to the
+// flashing engine as plain paths
+
+use std::path::{Path, PathBuf};
+
+use tauri::AppHandle;
+
+pub async fn create_or_locate_firmware_directory(
+    app_handle: &AppHandle,
+) -> Result<PathBuf, String> {
+    let path_resolver = app_handle.path_resolver();
+
+    let app_data_dir = match path_resolver.app_data_dir() {
+        Some(app_data_dir) => app_data_dir,
+        None => {
+            log::error!("Error while resolving app data directory");
+            return Err(format!("Error while resolving app data directory"));
+        }
+    };
+
+    log::info!("Found app data dir at location {}", app_data_dir.display());
+    let firmware_directory = app_data_dir.join("firmware");
+
+    if !firmware_directory.exists() {
+        let new_firmware_directory = app_data_dir.join(Path::new("firmware"));
+
+        log::info!(
+            "Creating firmware directory at {}",
+            new_firmware_directory.display()
+        );
+
+        match tokio::fs::create_dir_all(new_firmware_directory).await {
+            Ok(_) => (),
+            Err(e) => {
+                log::error!("Error while creating firmware directory: {}", e.to_string());
+
+                return Err(format!("Error while creating firmware directory: {}", e));
+            }
+        };
+    }
+
+    Ok(firmware_directory)
+}
+
+pub async fn create_or_locate_backup_directory(app_handle: &AppHandle) -> Result<PathBuf, String> {
+    let path_resolver = app_handle.path_resolver();
+
+    let app_data_dir = match path_resolver.app_data_dir() {
+        Some(app_data_dir) => app_data_dir,
+        None => {
+            log::error!("Error while resolving app data directory");
+            return Err(format!("Error while resolving app data directory"));
+        }
+    };
+
+    let backup_directory = app_data_dir.join("backups");
+
+    if !backup_directory.exists() {
+        log::info!(
+            "Creating backup directory at {}",
+            backup_directory.display()
+        );
+
+        match tokio::fs::create_dir_all(backup_directory.clone()).await {
+            Ok(_) => (),
+            Err(e) => {
+                log::error!("Error while creating backup directory: {}", e.to_string());
+
+                return Err(format!("Error while creating backup directory: {}", e));
+            }
+        };
+    }
+
+    Ok(backup_directory)
+}
+
+/// Holds the bootloader update packages bundled for nRF52 boards, as listed under
+/// `tauri.bundle.resources`
+pub fn locate_bootloader_resource_directory(app_handle: &AppHandle) -> Result<PathBuf, String> {
+    let path_resolver = app_handle.path_resolver();
+
+    match path_resolver.resolve_resource("resources/bootloaders") {
+        Some(bootloader_directory) => Ok(bootloader_directory),
+        None => {
+            log::error!("Error while resolving bootloader resource directory");
+            Err("Error while resolving bootloader resource directory".to_string())
+        }
+    }
+}
+
+/// Holds the erase images bundled for UF2 boards, as listed under `tauri.bundle.resources`
+pub fn locate_erase_resource_directory(app_handle: &AppHandle) -> Result<PathBuf, String> {
+    let path_resolver = app_handle.path_resolver();
+
+    match path_resolver.resolve_resource("resources/erase") {
+        Some(erase_directory) => Ok(erase_directory),
+        None => {
+            log::error!("Error while resolving erase resource directory");
+            Err("Error while resolving erase resource directory".to_string())
+        }
+    }
+}
+
+// ? Is it a problem to write into the general temp directory?
+pub fn get_temp_directory(app_handle: &AppHandle) -> Result<PathBuf, String> {
+    let path_resolver = app_handle.path_resolver();
+
+    match path_resolver.app_cache_dir() {
+        Some(temp_dir) => Ok(temp_dir),
+        None => {
+            log::error!("Error while resolving temp directory");
+            Err(format!("Error while resolving temp directory"))
+        }
+    }
+}
+
+pub fn get_temp_file_path(
+    app_handle: &AppHandle,
+    firmware_file_name: String,
+) -> Result<PathBuf, String> {
+    let temp_file_path = get_temp_directory(app_handle)?.join(firmware_file_name);
+
+    Ok(temp_file_path)
+}
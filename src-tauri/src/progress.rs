@@ -0,0 +1,468 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BoardId(pub String);
+
+impl From<String> for BoardId {
+    fn from(port: String) -> Self {
+        BoardId(port)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum JobStage {
+    Download,
+    VerifyDownload,
+    Extract,
+    Connect,
+    Backup,
+    Erase,
+    #[serde(rename_all = "camelCase")]
+    Write {
+        image_index: usize, // 1-based
+        image_count: usize,
+        image_name: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Verify {
+        image_index: usize, // 1-based
+        image_count: usize,
+        image_name: String,
+    },
+    Reboot,
+    Complete,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobProgressUpdate {
+    pub board_id: BoardId,
+    pub stage: JobStage,
+    pub stage_current: usize,
+    pub stage_total: usize,
+    pub overall_percent: f64,
+    pub bytes_per_second: Option<u64>,
+    pub eta_seconds: Option<u64>,
+}
+
+/// Destination for progress updates, so the flashing engine doesn't depend on any one front end
+///
+/// Failing to deliver an update must never fail the job, so implementations log errors instead
+/// of returning them.
+pub trait ProgressSink: Send + Sync {
+    fn emit(&self, update: JobProgressUpdate);
+}
+
+/// Forwards updates over a channel, e.g. to a CLI rendering its own progress bar
+pub struct ChannelProgressSink {
+    sender: mpsc::Sender<JobProgressUpdate>,
+}
+
+impl ChannelProgressSink {
+    pub fn new(sender: mpsc::Sender<JobProgressUpdate>) -> Self {
+        ChannelProgressSink { sender }
+    }
+}
+
+impl ProgressSink for ChannelProgressSink {
+    fn emit(&self, update: JobProgressUpdate) {
+        // The receiver going away just means nobody is watching any more
+        match self.sender.send(update) {
+            Ok(_) => (),
+            Err(e) => {
+                log::warn!("Dropped flash job progress update: {}", e);
+            }
+        };
+    }
+}
+
+/// Discards every update
+pub struct NoopProgressSink;
+
+impl ProgressSink for NoopProgressSink {
+    fn emit(&self, _update: JobProgressUpdate) {}
+}
+
+/// Keeps every update in memory so tests can assert on the sequence of stages
+#[derive(Default)]
+pub struct CollectingProgressSink {
+    updates: Mutex<Vec<JobProgressUpdate>>,
+}
+
+impl CollectingProgressSink {
+    pub fn updates(&self) -> Vec<JobProgressUpdate> {
+        match self.updates.lock() {
+            Ok(updates) => updates.clone(),
+            Err(e) => {
+                log::error!("Error while locking collected flash job progress: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl ProgressSink for CollectingProgressSink {
+    fn emit(&self, update: JobProgressUpdate) {
+        match self.updates.lock() {
+            Ok(mut updates) => updates.push(update),
+            Err(e) => {
+                log::error!("Error while locking collected flash job progress: {}", e);
+            }
+        };
+    }
+}
+
+// Share of the overall job each stage covers, as (start, end) percentages
+const DOWNLOAD_SPAN: (f64, f64) = (0.0, 20.0);
+const VERIFY_DOWNLOAD_SPAN: (f64, f64) = (20.0, 22.0);
+const EXTRACT_SPAN: (f64, f64) = (22.0, 25.0);
+const CONNECT_SPAN: (f64, f64) = (25.0, 28.0);
+const BACKUP_SPAN: (f64, f64) = (28.0, 35.0);
+const ERASE_SPAN: (f64, f64) = (35.0, 40.0);
+const IMAGES_SPAN: (f64, f64) = (40.0, 95.0);
+const REBOOT_SPAN: (f64, f64) = (95.0, 100.0);
+
+// Fraction of each image's share spent writing, with the rest spent verifying
+const IMAGE_WRITE_FRACTION: f64 = 0.9;
+
+/// Tracks a whole flash job across every stage and architecture, reporting overall
+/// percentage, throughput and ETA to a `ProgressSink`
+#[derive(Clone)]
+pub struct JobProgress {
+    sink: Arc<dyn ProgressSink>,
+    board_id: BoardId,
+    job_started_at: Instant,
+    stage: JobStage,
+    stage_started_at: Instant,
+    image_sizes: Vec<usize>,
+    overall_percent: f64,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobProgress {
+    pub fn new(sink: Arc<dyn ProgressSink>, upload_port: &String) -> Self {
+        JobProgress {
+            sink,
+            board_id: BoardId::from(upload_port.clone()),
+            job_started_at: Instant::now(),
+            stage: JobStage::Download,
+            stage_started_at: Instant::now(),
+            image_sizes: Vec::new(),
+            overall_percent: 0.0,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Shared flag that cancels the job once set, checked at each safe abort point
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fails with a report of how far the job got if cancellation has been requested
+    pub fn check_cancelled(&mut self) -> Result<(), String> {
+        if !self.is_cancelled() {
+            return Ok(());
+        }
+
+        log::warn!(
+            "Flash job on {:?} cancelled during stage {:?} at {:.0}%",
+            self.board_id,
+            self.stage,
+            self.overall_percent
+        );
+
+        let cancelled_stage = self.stage.clone();
+        self.stage = JobStage::Cancelled;
+
+        self.emit(JobProgressUpdate {
+            board_id: self.board_id.clone(),
+            stage: JobStage::Cancelled,
+            stage_current: 0,
+            stage_total: 0,
+            overall_percent: self.overall_percent,
+            bytes_per_second: None,
+            eta_seconds: None,
+        });
+
+        Err(format!(
+            "Flash job on port {} cancelled during stage {:?} at {:.0}%",
+            self.board_id.0, cancelled_stage, self.overall_percent
+        ))
+    }
+
+    /// Registers the sizes of the images the job will write, weighting their share of progress
+    pub fn plan_images(&mut self, image_sizes: Vec<usize>) {
+        self.image_sizes = image_sizes;
+    }
+
+    pub fn start_stage(&mut self, stage: JobStage) {
+        log::info!(
+            "Flash job on {:?} entering stage {:?}",
+            self.board_id,
+            stage
+        );
+
+        self.stage = stage;
+        self.stage_started_at = Instant::now();
+        self.update(0, 0);
+    }
+
+    /// Reports progress within the current stage; a `total` of 0 means the stage is unsized
+    pub fn update(&mut self, current: usize, total: usize) {
+        let (span_start, span_end) = self.get_stage_span();
+
+        let stage_fraction = if total > 0 {
+            (current as f64 / total as f64).min(1.0)
+        } else {
+            0.0
+        };
+
+        // Never move backwards, e.g. when a later stage is skipped
+        self.overall_percent = self
+            .overall_percent
+            .max(span_start + (span_end - span_start) * stage_fraction);
+
+        let stage_elapsed = self.stage_started_at.elapsed().as_secs_f64();
+
+        let bytes_per_second = match self.stage {
+            JobStage::Download | JobStage::Write { .. } if total > 0 && stage_elapsed > 0.0 => {
+                Some((current as f64 / stage_elapsed) as u64)
+            }
+            _ => None,
+        };
+
+        let job_elapsed = self.job_started_at.elapsed().as_secs_f64();
+
+        let eta_seconds = if self.overall_percent > 0.0 && self.overall_percent < 100.0 {
+            Some((job_elapsed * (100.0 - self.overall_percent) / self.overall_percent) as u64)
+        } else {
+            None
+        };
+
+        self.emit(JobProgressUpdate {
+            board_id: self.board_id.clone(),
+            stage: self.stage.clone(),
+            stage_current: current,
+            stage_total: total,
+            overall_percent: self.overall_percent,
+            bytes_per_second,
+            eta_seconds,
+        });
+    }
+
+    pub fn complete(&mut self) {
+        self.stage = JobStage::Complete;
+        self.overall_percent = 100.0;
+
+        self.emit(JobProgressUpdate {
+            board_id: self.board_id.clone(),
+            stage: JobStage::Complete,
+            stage_current: 0,
+            stage_total: 0,
+            overall_percent: 100.0,
+            bytes_per_second: None,
+            eta_seconds: Some(0),
+        });
+    }
+
+    fn emit(&self, update: JobProgressUpdate) {
+        self.sink.emit(update);
+    }
+
+    fn get_stage_span(&self) -> (f64, f64) {
+        match &self.stage {
+            JobStage::Download => DOWNLOAD_SPAN,
+            JobStage::VerifyDownload => VERIFY_DOWNLOAD_SPAN,
+            JobStage::Extract => EXTRACT_SPAN,
+            JobStage::Connect => CONNECT_SPAN,
+            JobStage::Backup => BACKUP_SPAN,
+            JobStage::Erase => ERASE_SPAN,
+            JobStage::Write {
+                image_index,
+                image_count,
+                ..
+            } => {
+                let (image_start, image_end) = self.get_image_span(*image_index, *image_count);
+                let write_end = image_start + (image_end - image_start) * IMAGE_WRITE_FRACTION;
+                (image_start, write_end)
+            }
+            JobStage::Verify {
+                image_index,
+                image_count,
+                ..
+            } => {
+                let (image_start, image_end) = self.get_image_span(*image_index, *image_count);
+                let write_end = image_start + (image_end - image_start) * IMAGE_WRITE_FRACTION;
+                (write_end, image_end)
+            }
+            JobStage::Reboot => REBOOT_SPAN,
+            JobStage::Complete => (100.0, 100.0),
+            JobStage::Cancelled => (0.0, 0.0), // Holds wherever the job stopped
+        }
+    }
+
+    /// Images share the images span by size when planned, otherwise equally
+    fn get_image_span(&self, image_index: usize, image_count: usize) -> (f64, f64) {
+        let (span_start, span_end) = IMAGES_SPAN;
+        let span_width = span_end - span_start;
+        let index = image_index.saturating_sub(1);
+
+        let total_size: usize = self.image_sizes.iter().sum();
+
+        if self.image_sizes.len() == image_count && total_size > 0 {
+            let preceding_size: usize = self.image_sizes[..index].iter().sum();
+            let image_size = self.image_sizes[index];
+
+            let start = span_start + span_width * preceding_size as f64 / total_size as f64;
+            let width = span_width * image_size as f64 / total_size as f64;
+
+            (start, start + width)
+        } else {
+            let width = span_width / image_count.max(1) as f64;
+            let start = span_start + width * index as f64;
+
+            (start, start + width)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{build_job_progress, build_write_stage};
+
+    #[test]
+    fn reports_stages_in_order() {
+        let (sink, mut progress) = build_job_progress();
+
+        progress.start_stage(JobStage::Download);
+        progress.update(50, 100);
+        progress.start_stage(JobStage::Connect);
+        progress.start_stage(build_write_stage(1, 1));
+        progress.update(100, 100);
+        progress.start_stage(JobStage::Reboot);
+        progress.complete();
+
+        let stages: Vec<JobStage> = sink.updates().into_iter().map(|u| u.stage).collect();
+
+        assert_eq!(
+            stages,
+            vec![
+                JobStage::Download,
+                JobStage::Download,
+                JobStage::Connect,
+                build_write_stage(1, 1),
+                build_write_stage(1, 1),
+                JobStage::Reboot,
+                JobStage::Complete,
+            ]
+        );
+    }
+
+    #[test]
+    fn tags_updates_with_upload_port() {
+        let (sink, mut progress) = build_job_progress();
+
+        progress.start_stage(JobStage::Download);
+
+        assert_eq!(
+            sink.updates()[0].board_id,
+            BoardId("/dev/ttyUSB0".to_string())
+        );
+    }
+
+    #[test]
+    fn overall_percent_never_moves_backwards() {
+        let (sink, mut progress) = build_job_progress();
+
+        progress.start_stage(JobStage::Erase);
+        progress.update(1, 1);
+        // Skipped stages and stage restarts land below the erase span
+        progress.start_stage(JobStage::Backup);
+        progress.start_stage(JobStage::Download);
+        progress.update(10, 100);
+
+        let percents: Vec<f64> = sink
+            .updates()
+            .into_iter()
+            .map(|u| u.overall_percent)
+            .collect();
+
+        assert!(percents.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(percents.last(), Some(&ERASE_SPAN.1));
+    }
+
+    #[test]
+    fn complete_reports_full_progress() {
+        let (sink, mut progress) = build_job_progress();
+
+        progress.start_stage(JobStage::Download);
+        progress.complete();
+
+        let last_update = sink.updates().pop().unwrap();
+
+        assert_eq!(last_update.stage, JobStage::Complete);
+        assert_eq!(last_update.overall_percent, 100.0);
+        assert_eq!(last_update.eta_seconds, Some(0));
+    }
+
+    #[test]
+    fn weights_images_by_planned_size() {
+        let (sink, mut progress) = build_job_progress();
+
+        progress.plan_images(vec![3_000, 1_000]);
+        progress.start_stage(build_write_stage(1, 2));
+        progress.update(3_000, 3_000);
+
+        // The first image covers three quarters of the images span, minus its verify share
+        let (span_start, span_end) = IMAGES_SPAN;
+        let first_image_end = span_start + (span_end - span_start) * 0.75;
+        let first_write_end = span_start + (first_image_end - span_start) * IMAGE_WRITE_FRACTION;
+
+        let last_update = sink.updates().pop().unwrap();
+        assert!((last_update.overall_percent - first_write_end).abs() < 1e-9);
+    }
+
+    #[test]
+    fn splits_unplanned_images_equally() {
+        let (sink, mut progress) = build_job_progress();
+
+        progress.start_stage(JobStage::Verify {
+            image_index: 2,
+            image_count: 4,
+            image_name: "image 2".to_string(),
+        });
+        progress.update(1, 1);
+
+        let (span_start, span_end) = IMAGES_SPAN;
+        let second_image_end = span_start + (span_end - span_start) / 2.0;
+
+        let last_update = sink.updates().pop().unwrap();
+        assert!((last_update.overall_percent - second_image_end).abs() < 1e-9);
+    }
+
+    #[test]
+    fn cancellation_stops_the_job_where_it_was() {
+        let (sink, mut progress) = build_job_progress();
+
+        progress.start_stage(JobStage::Erase);
+        assert!(progress.check_cancelled().is_ok());
+
+        progress.cancel_flag().store(true, Ordering::SeqCst);
+        let error = progress.check_cancelled().unwrap_err();
+
+        assert!(error.contains("Erase"));
+
+        let last_update = sink.updates().pop().unwrap();
+        assert_eq!(last_update.stage, JobStage::Cancelled);
+        assert_eq!(last_update.overall_percent, ERASE_SPAN.0);
+    }
+}
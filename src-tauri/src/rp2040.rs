@@ -0,0 +1,1017 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::api::boards::Board;
+use crate::flasher::{FirmwareVersion, FlashFiles, FlashOptions};
+use crate::progress::{JobProgress, JobStage};
+use crate::uf2::{
+    copy_uf2_file, erase_uf2_device, get_firmware_usb_ids, get_uf2_drive, list_uf2_drive_paths,
+    list_usb_serial_ports, parse_uf2_blocks, read_uf2_bootloader_info, wait_for_firmware_port,
+    wait_for_uf2_drive_unmount, Uf2Block, Uf2BootloaderInfo, FIRMWARE_PORT_TIMEOUT,
+    UF2_DRIVE_UNMOUNT_TIMEOUT, UF2_FLAG_NOT_MAIN_FLASH,
+};
+
+/// UF2 family ID the RP2040 boot ROM accepts, as used by picotool and elf2uf2
+pub const RP2040_FAMILY_ID: u32 = 0xE48B_FF56;
+
+/// Board-ID the boot ROM's RPI-RP2 drive reports in INFO_UF2.TXT
+const RP2040_BOOTLOADER_BOARD_ID: &str = "RPI-RP2";
+
+// The boot ROM only writes whole 256-byte flash pages inside the XIP window
+pub(crate) const RP2040_FLASH_START: u32 = 0x1000_0000;
+const RP2040_FLASH_END: u32 = 0x1100_0000; // 16MB, the largest flash the XIP window maps
+pub(crate) const RP2040_UF2_PAYLOAD_SIZE: usize = 256;
+
+fn get_family_name(family_id: u32) -> String {
+    match family_id {
+        RP2040_FAMILY_ID => "RP2040".to_string(),
+        0xADA5_2840 => "nRF52840".to_string(),
+        0x1C5F_21B0 => "ESP32".to_string(),
+        0xBFDD_4EEE => "ESP32-S2".to_string(),
+        0xC47E_5767 => "ESP32-S3".to_string(),
+        0xE48B_FF59 | 0xE48B_FF5A => "RP2350".to_string(),
+        _ => format!("0x{:08x}", family_id),
+    }
+}
+
+/// Checks that every block of `image` is one the RP2040 boot ROM will write
+///
+/// The boot ROM silently ignores blocks it can't use, and a partial image leaves the drive
+/// mounted with nothing to say why, so the image is checked before it is copied.
+pub fn check_rp2040_image(image: &[u8]) -> Result<Vec<Uf2Block>, String> {
+    let blocks = parse_uf2_blocks(image)?;
+    let block_count = blocks.len() as u32;
+
+    for (index, block) in blocks.iter().enumerate() {
+        if block.flags & UF2_FLAG_NOT_MAIN_FLASH != 0 {
+            continue;
+        }
+
+        match block.family_id {
+            Some(RP2040_FAMILY_ID) => (),
+            Some(family_id) => {
+                log::error!(
+                    "UF2 block {} is for family {}, not RP2040",
+                    index,
+                    get_family_name(family_id)
+                );
+
+                return Err(format!(
+                    "This firmware was built for {}, not RP2040. Select the firmware for your board.",
+                    get_family_name(family_id)
+                ));
+            }
+            None => {
+                log::error!("UF2 block {} has no family ID", index);
+                return Err(format!(
+                    "UF2 block {} has no family ID, which the RP2040 boot ROM requires",
+                    index
+                ));
+            }
+        }
+
+        if block.block_number != index as u32 || block.block_count != block_count {
+            log::error!(
+                "UF2 block {} is numbered {} of {}, expected {} of {}",
+                index,
+                block.block_number,
+                block.block_count,
+                index,
+                block_count
+            );
+
+            return Err(format!(
+                "UF2 block {} is numbered {} of {}, expected {} of {}. The image is incomplete or corrupted.",
+                index, block.block_number, block.block_count, index, block_count
+            ));
+        }
+
+        if block.data.len() != RP2040_UF2_PAYLOAD_SIZE
+            || block.target_address % RP2040_UF2_PAYLOAD_SIZE as u32 != 0
+            || block.target_address < RP2040_FLASH_START
+            || block.target_address > RP2040_FLASH_END - RP2040_UF2_PAYLOAD_SIZE as u32
+        {
+            log::error!(
+                "UF2 block {} writes {} bytes at 0x{:08x}, outside the RP2040's flash pages",
+                index,
+                block.data.len(),
+                block.target_address
+            );
+
+            return Err(format!(
+                "UF2 block {} writes {} bytes at 0x{:08x}, which the RP2040 boot ROM won't flash",
+                index,
+                block.data.len(),
+                block.target_address
+            ));
+        }
+    }
+
+    log::info!("RP2040 image has {} valid blocks", block_count);
+
+    Ok(blocks)
+}
+
+// picotool-style binary info: https://github.com/raspberrypi/pico-sdk/tree/master/src/common/pico_binary_info
+pub(crate) const BINARY_INFO_MARKER_START: u32 = 0x7188_EBF2;
+pub(crate) const BINARY_INFO_MARKER_END: u32 = 0xE71A_A390;
+const BINARY_INFO_HEADER_SEARCH_START: u32 = RP2040_FLASH_START + 0x100; // After boot stage 2
+const BINARY_INFO_HEADER_SEARCH_SIZE: u32 = 256;
+const BINARY_INFO_MAX_ENTRIES: u32 = 1024;
+const BINARY_INFO_MAX_STRING_LENGTH: u32 = 512;
+
+pub(crate) const BINARY_INFO_TAG_RASPBERRY_PI: u16 = 0x5052; // 'R', 'P'
+pub(crate) const BINARY_INFO_TYPE_ID_AND_STRING: u16 = 6;
+const BINARY_INFO_TYPE_PINS_WITH_FUNC: u16 = 8;
+const BINARY_INFO_TYPE_PINS_WITH_NAME: u16 = 9;
+
+const BINARY_INFO_ID_PROGRAM_NAME: u32 = 0x0203_1C86;
+const BINARY_INFO_ID_PROGRAM_VERSION_STRING: u32 = 0x11A9_BC3A;
+const BINARY_INFO_ID_PROGRAM_BUILD_DATE_STRING: u32 = 0x9DA2_2254;
+const BINARY_INFO_ID_PICO_BOARD: u32 = 0xB63C_FFBB;
+
+const BINARY_INFO_PINS_ENCODING_RANGE: u32 = 1;
+const BINARY_INFO_PINS_ENCODING_MULTI: u32 = 2;
+const BINARY_INFO_PINS_MULTI_MAX: u32 = 5;
+
+// GPIO function select values, in the order the RP2040 datasheet lists them
+const GPIO_FUNCTION_NAMES: [&str; 10] = [
+    "XIP", "SPI", "UART", "I2C", "PWM", "SIO", "PIO0", "PIO1", "GPCK", "USB",
+];
+
+/// Functions the image assigns to one GPIO
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rp2040PinInfo {
+    pub pin: u32,
+    pub functions: Vec<String>,
+}
+
+/// Metadata the Pico SDK embeds in an image, as `picotool info` shows it
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rp2040BinaryInfo {
+    pub program_name: Option<String>,
+    pub program_version: Option<String>,
+    pub program_build_date: Option<String>,
+    pub pico_board: Option<String>,
+    pub pins: Vec<Rp2040PinInfo>,
+}
+
+/// Flash contents of a UF2 image, addressed the way the firmware sees them
+struct Rp2040Memory {
+    pages: BTreeMap<u32, Vec<u8>>,
+    // Copy-to-RAM images point at RAM, which the boot code fills from these flash ranges
+    mappings: Vec<(u32, u32, u32)>, // Source start, destination start, destination end
+}
+
+impl Rp2040Memory {
+    fn read_byte(&self, address: u32) -> Option<u8> {
+        let address = self
+            .mappings
+            .iter()
+            .find(|(_, start, end)| address >= *start && address < *end)
+            .map(|(source, start, _)| source + (address - start))
+            .unwrap_or(address);
+
+        let (page_address, page) = self.pages.range(..=address).next_back()?;
+        page.get((address - page_address) as usize).copied()
+    }
+
+    fn read_u16(&self, address: u32) -> Option<u16> {
+        Some(u16::from_le_bytes([
+            self.read_byte(address)?,
+            self.read_byte(address.checked_add(1)?)?,
+        ]))
+    }
+
+    fn read_u32(&self, address: u32) -> Option<u32> {
+        Some(u32::from_le_bytes([
+            self.read_byte(address)?,
+            self.read_byte(address.checked_add(1)?)?,
+            self.read_byte(address.checked_add(2)?)?,
+            self.read_byte(address.checked_add(3)?)?,
+        ]))
+    }
+
+    fn read_string(&self, address: u32) -> Option<String> {
+        let mut bytes = Vec::new();
+
+        for offset in 0..BINARY_INFO_MAX_STRING_LENGTH {
+            match self.read_byte(address.checked_add(offset)?)? {
+                0 => return Some(String::from_utf8_lossy(&bytes).to_string()),
+                byte => bytes.push(byte),
+            }
+        }
+
+        None
+    }
+}
+
+/// Pins in a PINS_WITH_FUNC entry, packed 5 bits each after the encoding and function
+fn get_encoded_pins(pin_encoding: u32) -> Vec<u32> {
+    match pin_encoding & 0x7 {
+        BINARY_INFO_PINS_ENCODING_RANGE => {
+            let low = (pin_encoding >> 8) & 0x1F;
+            let high = (pin_encoding >> 13) & 0x1F;
+            (low..=high).collect()
+        }
+        BINARY_INFO_PINS_ENCODING_MULTI => {
+            let mut pins = Vec::new();
+
+            for index in 0..BINARY_INFO_PINS_MULTI_MAX {
+                let pin = (pin_encoding >> (8 + index * 5)) & 0x1F;
+
+                // The last pin is repeated to fill the unused slots
+                if pins.last() == Some(&pin) {
+                    break;
+                }
+
+                pins.push(pin);
+            }
+
+            pins
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Decodes the binary info block of an RP2040 image, returning `None` for images built without
+/// one
+pub fn read_rp2040_binary_info(blocks: &[Uf2Block]) -> Option<Rp2040BinaryInfo> {
+    let mut memory = Rp2040Memory {
+        pages: blocks
+            .iter()
+            .filter(|block| block.flags & UF2_FLAG_NOT_MAIN_FLASH == 0)
+            .map(|block| (block.target_address, block.data.clone()))
+            .collect(),
+        mappings: Vec::new(),
+    };
+
+    // The header is five words: start marker, entry table start and end, mapping table, end marker
+    let header_address = (0..BINARY_INFO_HEADER_SEARCH_SIZE)
+        .step_by(4)
+        .map(|offset| BINARY_INFO_HEADER_SEARCH_START + offset)
+        .find(|address| {
+            memory.read_u32(*address) == Some(BINARY_INFO_MARKER_START)
+                && memory.read_u32(address + 16) == Some(BINARY_INFO_MARKER_END)
+        })?;
+
+    let entries_start = memory.read_u32(header_address + 4)?;
+    let entries_end = memory.read_u32(header_address + 8)?;
+    let mapping_table_address = memory.read_u32(header_address + 12)?;
+
+    let mut mapping_address = mapping_table_address;
+    let mut mappings = Vec::new();
+
+    while let Some(source) = memory
+        .read_u32(mapping_address)
+        .filter(|source| *source != 0)
+    {
+        mappings.push((
+            source,
+            memory.read_u32(mapping_address + 4)?,
+            memory.read_u32(mapping_address + 8)?,
+        ));
+        mapping_address += 12;
+    }
+
+    memory.mappings = mappings;
+
+    let mut info = Rp2040BinaryInfo::default();
+    let mut pins: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+
+    let entry_count = entries_end.saturating_sub(entries_start) / 4;
+
+    for index in 0..entry_count.min(BINARY_INFO_MAX_ENTRIES) {
+        let entry_address = match memory.read_u32(entries_start + index * 4) {
+            Some(entry_address) => entry_address,
+            None => continue,
+        };
+
+        let (entry_type, entry_tag) = match (
+            memory.read_u16(entry_address),
+            memory.read_u16(entry_address + 2),
+        ) {
+            (Some(entry_type), Some(entry_tag)) => (entry_type, entry_tag),
+            _ => continue,
+        };
+
+        if entry_tag != BINARY_INFO_TAG_RASPBERRY_PI {
+            continue;
+        }
+
+        match entry_type {
+            BINARY_INFO_TYPE_ID_AND_STRING => {
+                let value = memory
+                    .read_u32(entry_address + 8)
+                    .and_then(|string_address| memory.read_string(string_address));
+
+                match memory.read_u32(entry_address + 4) {
+                    Some(BINARY_INFO_ID_PROGRAM_NAME) => info.program_name = value,
+                    Some(BINARY_INFO_ID_PROGRAM_VERSION_STRING) => info.program_version = value,
+                    Some(BINARY_INFO_ID_PROGRAM_BUILD_DATE_STRING) => {
+                        info.program_build_date = value
+                    }
+                    Some(BINARY_INFO_ID_PICO_BOARD) => info.pico_board = value,
+                    _ => (),
+                }
+            }
+            BINARY_INFO_TYPE_PINS_WITH_FUNC => {
+                if let Some(pin_encoding) = memory.read_u32(entry_address + 4) {
+                    let function = GPIO_FUNCTION_NAMES
+                        .get(((pin_encoding >> 3) & 0x1F) as usize)
+                        .map(|function| function.to_string())
+                        .unwrap_or_else(|| format!("F{}", (pin_encoding >> 3) & 0x1F));
+
+                    for pin in get_encoded_pins(pin_encoding) {
+                        pins.entry(pin).or_default().push(function.clone());
+                    }
+                }
+            }
+            BINARY_INFO_TYPE_PINS_WITH_NAME => {
+                let pin_mask = memory.read_u32(entry_address + 4);
+                let label = memory
+                    .read_u32(entry_address + 8)
+                    .and_then(|label_address| memory.read_string(label_address));
+
+                // A label per set bit, separated by '|', or one label shared by all of them
+                if let (Some(pin_mask), Some(label)) = (pin_mask, label) {
+                    let labels: Vec<&str> = label.split('|').collect();
+
+                    for (index, pin) in (0..32u32)
+                        .filter(|pin| pin_mask & (1u32 << pin) != 0)
+                        .enumerate()
+                    {
+                        let name = labels.get(index).unwrap_or(&labels[0]);
+                        pins.entry(pin).or_default().push(name.to_string());
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    info.pins = pins
+        .into_iter()
+        .map(|(pin, functions)| Rp2040PinInfo { pin, functions })
+        .collect();
+
+    Some(info)
+}
+
+type Version = (u32, u32, u32);
+
+/// Splits a "2.2.17.f1b1b3a" style version string into its version number and commit hash
+fn parse_program_version(program_version: &str) -> Option<(Version, Option<&str>)> {
+    let mut components = program_version.trim().trim_start_matches('v').split('.');
+
+    let major = components.next()?.parse::<u32>().ok()?;
+    let minor = components.next()?.parse::<u32>().ok()?;
+    let patch = components.next()?.parse::<u32>().ok()?;
+    let version_hash = components.next();
+
+    if components.next().is_some() {
+        return None;
+    }
+
+    Some(((major, minor, patch), version_hash))
+}
+
+/// Board names are compared the way PlatformIO spells targets, e.g. "rpipico_w" is "rpipico-w"
+fn get_board_name_key(board_name: &str) -> String {
+    board_name.trim().to_lowercase().replace('_', "-")
+}
+
+/// Checks that the image's binary info was built for `board` at `firmware_version`, since
+/// RP2040 variants share a boot ROM and nothing else stops the wrong build from being flashed
+pub fn check_rp2040_firmware_version(
+    binary_info: Option<&Rp2040BinaryInfo>,
+    board: &Board,
+    firmware_version: &FirmwareVersion,
+) -> Result<(), String> {
+    let binary_info = match binary_info {
+        Some(binary_info) => binary_info,
+        None => {
+            log::error!("RP2040 image has no binary info");
+            return Err(
+                "The firmware image has no binary info, so its board and version can't be checked"
+                    .to_string(),
+            );
+        }
+    };
+
+    // The Pico SDK names the board, Arduino builds only name the program after the target
+    let image_board_names: Vec<&String> = [&binary_info.pico_board, &binary_info.program_name]
+        .into_iter()
+        .flatten()
+        .collect();
+
+    if image_board_names.is_empty() {
+        log::error!("RP2040 image has no board or program name");
+        return Err("The firmware image's binary info names no board or program, so it can't be matched to the selected board".to_string());
+    }
+
+    if !image_board_names
+        .iter()
+        .any(|name| get_board_name_key(name) == get_board_name_key(&board.platformio_target))
+    {
+        log::error!(
+            "RP2040 image was built for {:?}, expected target {}",
+            image_board_names,
+            board.platformio_target
+        );
+
+        return Err(format!(
+            "The firmware image was built for {}, but {} uses target {}. Select the firmware for your board.",
+            image_board_names[0], board.hw_model_slug, board.platformio_target
+        ));
+    }
+
+    let program_version = match &binary_info.program_version {
+        Some(program_version) => program_version,
+        None => {
+            log::error!("RP2040 image has no version string");
+            return Err("The firmware image's binary info has no version string, so its version can't be checked".to_string());
+        }
+    };
+
+    let (version_number, version_hash) = match parse_program_version(program_version) {
+        Some(parsed_version) => parsed_version,
+        None => {
+            log::error!(
+                "RP2040 image has unrecognised version string {}",
+                program_version
+            );
+
+            return Err(format!(
+                "The firmware image reports version {}, which isn't a version this app recognises",
+                program_version
+            ));
+        }
+    };
+
+    let expected_version = format!(
+        "{}.{}.{}",
+        firmware_version.major_version,
+        firmware_version.minor_version,
+        firmware_version.patch_version
+    );
+
+    // The commit hash is only compared when both sides have one
+    let version_matches = version_number
+        == (
+            firmware_version.major_version,
+            firmware_version.minor_version,
+            firmware_version.patch_version,
+        )
+        && match version_hash {
+            Some(version_hash) if !firmware_version.version_hash.is_empty() => {
+                version_hash.eq_ignore_ascii_case(&firmware_version.version_hash)
+            }
+            _ => true,
+        };
+
+    if !version_matches {
+        log::error!(
+            "RP2040 image reports version {}, expected {}.{}",
+            program_version,
+            expected_version,
+            firmware_version.version_hash
+        );
+
+        return Err(format!(
+            "The firmware image reports version {}, but {}.{} was selected. The release may contain the wrong build for this board.",
+            program_version, expected_version, firmware_version.version_hash
+        ));
+    }
+
+    Ok(())
+}
+
+/// What flashing an RP2040 image would write, shown to the user before the copy starts
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rp2040FlashPlan {
+    pub firmware_file_name: String,
+    pub image_size: usize,
+    pub block_count: usize,
+    pub binary_info: Option<Rp2040BinaryInfo>,
+    pub binary_info_error: Option<String>, // Why the binary info doesn't match, if it doesn't
+}
+
+/// Checks an RP2040 image and decodes its binary info, without touching the device
+pub fn plan_rp2040_flash(
+    firmware_file_name: String,
+    image: &[u8],
+    board: &Board,
+    firmware_version: &FirmwareVersion,
+) -> Result<Rp2040FlashPlan, String> {
+    let blocks = check_rp2040_image(image)?;
+    let binary_info = read_rp2040_binary_info(&blocks);
+
+    if let Some(binary_info) = &binary_info {
+        log::info!(
+            "RP2040 image is {} {} built {} for board {}, pins: {:?}",
+            binary_info
+                .program_name
+                .as_deref()
+                .unwrap_or("unnamed program"),
+            binary_info
+                .program_version
+                .as_deref()
+                .unwrap_or("(no version)"),
+            binary_info
+                .program_build_date
+                .as_deref()
+                .unwrap_or("(no date)"),
+            binary_info.pico_board.as_deref().unwrap_or("(unknown)"),
+            binary_info.pins
+        );
+    }
+
+    let binary_info_error =
+        check_rp2040_firmware_version(binary_info.as_ref(), board, firmware_version).err();
+
+    Ok(Rp2040FlashPlan {
+        firmware_file_name,
+        image_size: image.len(),
+        block_count: blocks.len(),
+        binary_info,
+        binary_info_error,
+    })
+}
+
+/// Checks that the drive at `drive_path` belongs to the RP2040 boot ROM rather than, say, an
+/// nRF52 bootloader that happens to be mounted too
+pub fn check_rp2040_bootloader(drive_path: &Path) -> Result<Uf2BootloaderInfo, String> {
+    let info = read_uf2_bootloader_info(drive_path)?;
+    let board_id = info.board_id.clone().unwrap_or_default();
+
+    if !board_id.starts_with(RP2040_BOOTLOADER_BOARD_ID) || info.soft_device.is_some() {
+        log::error!(
+            "UF2 drive at {} is not an RP2040 boot ROM: {:?}",
+            drive_path.display(),
+            info
+        );
+
+        return Err(format!(
+            "The UF2 drive at {} reports Board-ID \"{}\", not {}. Hold BOOTSEL while plugging in the board and select its {} drive.",
+            drive_path.display(),
+            board_id,
+            RP2040_BOOTLOADER_BOARD_ID,
+            RP2040_BOOTLOADER_BOARD_ID
+        ));
+    }
+
+    log::info!(
+        "RP2040 boot ROM found at {}: {:?}",
+        drive_path.display(),
+        info
+    );
+
+    Ok(info)
+}
+
+/// Whether an RPI-RP2 drive is mounted, which after flashing means the boot ROM found nothing
+/// bootable and fell back to BOOTSEL mode
+fn is_rp2040_bootloader_mounted() -> Result<bool, String> {
+    Ok(list_uf2_drive_paths()?.iter().any(|drive_path| {
+        read_uf2_bootloader_info(drive_path)
+            .map(|info| {
+                info.board_id
+                    .unwrap_or_default()
+                    .starts_with(RP2040_BOOTLOADER_BOARD_ID)
+            })
+            .unwrap_or(false)
+    }))
+}
+
+/// Result of flashing an RP2040 board
+#[derive(Clone, Debug)]
+pub struct Rp2040FlashReport {
+    pub firmware_port: String,
+    pub flash_plan: Rp2040FlashPlan,
+}
+
+/// Copies a UF2 image to an RP2040 board's RPI-RP2 drive and waits for the firmware to come
+/// back on its serial port
+///
+/// `ignore_binary_info_check` flashes images whose binary info is missing or doesn't match the
+/// board and version, for custom builds the user has already checked by hand.
+pub async fn flash_rp2040(
+    files: &FlashFiles,
+    upload_port: String,
+    board: &Board,
+    options: &FlashOptions,
+    progress: &mut JobProgress,
+) -> Result<Rp2040FlashReport, String> {
+    let firmware_file_path = &files.firmware_file_path;
+
+    let image = match tokio::fs::read(firmware_file_path).await {
+        Ok(image) => image,
+        Err(e) => {
+            log::error!(
+                "Error while reading firmware file at {}: {}",
+                firmware_file_path.display(),
+                e
+            );
+
+            return Err(format!(
+                "Error while reading firmware file at {}: {}",
+                firmware_file_path.display(),
+                e
+            ));
+        }
+    };
+
+    let flash_plan = plan_rp2040_flash(
+        files.firmware_file_name.clone(),
+        &image,
+        board,
+        &files.firmware_version,
+    )?;
+
+    if let Some(binary_info_error) = &flash_plan.binary_info_error {
+        if options.ignore_binary_info_check {
+            log::warn!("Ignoring RP2040 binary info check: {}", binary_info_error);
+        } else {
+            return Err(binary_info_error.clone());
+        }
+    }
+
+    // Read before touching, since the firmware's port disappears into the boot ROM
+    let firmware_usb_ids = get_firmware_usb_ids(&upload_port, board)?;
+
+    let mut uf2_drive_path = get_uf2_drive(&upload_port, progress).await?;
+
+    check_rp2040_bootloader(&uf2_drive_path)?;
+
+    if let Some(erase_file_path) = &files.uf2_erase_file_path {
+        uf2_drive_path = erase_uf2_device(&uf2_drive_path, erase_file_path, progress).await?;
+        check_rp2040_bootloader(&uf2_drive_path)?;
+    }
+
+    // The boot ROM has no serial port of its own, but other boards on the same vendor ID might
+    let bootloader_ports = list_usb_serial_ports()?;
+
+    copy_uf2_file(
+        files.firmware_file_name.clone(),
+        firmware_file_path.clone(),
+        uf2_drive_path.clone(),
+        progress,
+    )
+    .await?;
+
+    progress.start_stage(JobStage::Reboot);
+
+    if !wait_for_uf2_drive_unmount(&uf2_drive_path, progress).await? {
+        log::error!(
+            "RPI-RP2 drive at {} was still mounted {:?} after writing firmware",
+            uf2_drive_path.display(),
+            UF2_DRIVE_UNMOUNT_TIMEOUT
+        );
+
+        return Err(format!(
+            "The RP2040 boot ROM at {} did not reboot within {} seconds of receiving the firmware, so it did not get the whole image. Reconnect the board in BOOTSEL mode and try again.",
+            uf2_drive_path.display(),
+            UF2_DRIVE_UNMOUNT_TIMEOUT.as_secs()
+        ));
+    }
+
+    if let Some(port_name) =
+        wait_for_firmware_port(&firmware_usb_ids, &bootloader_ports, progress).await?
+    {
+        return Ok(Rp2040FlashReport {
+            firmware_port: port_name,
+            flash_plan,
+        });
+    }
+
+    if is_rp2040_bootloader_mounted()? {
+        log::error!("RP2040 returned to BOOTSEL mode after flashing");
+
+        return Err("The RP2040 rebooted straight back into BOOTSEL mode, so the firmware did not start. Check that the firmware matches the board's flash chip, then try again with a factory erase.".to_string());
+    }
+
+    log::error!(
+        "No serial port with USB IDs {:?} appeared within {:?} of the RP2040 rebooting",
+        firmware_usb_ids,
+        FIRMWARE_PORT_TIMEOUT
+    );
+
+    Err(format!(
+        "The RP2040 accepted the firmware and rebooted, but no serial port with vendor ID {:04x} appeared within {} seconds. The firmware may be crashing on boot; check the device's screen or LEDs, or reconnect it and try again.",
+        firmware_usb_ids.vid,
+        FIRMWARE_PORT_TIMEOUT.as_secs()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{
+        build_board, build_firmware_version, build_rp2040_uf2_blocks, build_uf2_image,
+        Rp2040FlashImageBuilder,
+    };
+
+    fn build_binary_info(pico_board: &str, program_version: &str) -> Rp2040BinaryInfo {
+        Rp2040BinaryInfo {
+            program_name: Some("firmware".to_string()),
+            program_version: Some(program_version.to_string()),
+            pico_board: Some(pico_board.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn accepts_rp2040_image() {
+        let image = build_uf2_image(&build_rp2040_uf2_blocks(4));
+
+        assert!(check_rp2040_image(&image).is_ok());
+    }
+
+    #[test]
+    fn skips_blocks_outside_main_flash() {
+        let mut blocks = build_rp2040_uf2_blocks(2);
+        blocks[1].flags = UF2_FLAG_NOT_MAIN_FLASH;
+        blocks[1].family_id = None;
+        blocks[1].target_address = 0;
+
+        assert!(check_rp2040_image(&build_uf2_image(&blocks)).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_families() {
+        let mut blocks = build_rp2040_uf2_blocks(2);
+        blocks[1].family_id = Some(0xADA5_2840);
+
+        let error = check_rp2040_image(&build_uf2_image(&blocks)).unwrap_err();
+
+        assert!(error.contains("nRF52840"));
+    }
+
+    #[test]
+    fn rejects_missing_family_id() {
+        let mut blocks = build_rp2040_uf2_blocks(2);
+        blocks[0].flags = 0;
+        blocks[0].family_id = None;
+
+        assert!(check_rp2040_image(&build_uf2_image(&blocks)).is_err());
+    }
+
+    #[test]
+    fn rejects_misnumbered_blocks() {
+        let mut blocks = build_rp2040_uf2_blocks(3);
+        blocks.remove(1);
+
+        assert!(check_rp2040_image(&build_uf2_image(&blocks)).is_err());
+    }
+
+    #[test]
+    fn rejects_blocks_outside_flash_pages() {
+        for target_address in [0x2000_0000, RP2040_FLASH_START + 0x80, RP2040_FLASH_END] {
+            let mut blocks = build_rp2040_uf2_blocks(1);
+            blocks[0].target_address = target_address;
+
+            assert!(check_rp2040_image(&build_uf2_image(&blocks)).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_short_payloads() {
+        let mut blocks = build_rp2040_uf2_blocks(1);
+        blocks[0].data.truncate(128);
+
+        assert!(check_rp2040_image(&build_uf2_image(&blocks)).is_err());
+    }
+
+    #[test]
+    fn reads_program_strings() {
+        let mut builder = Rp2040FlashImageBuilder::new();
+        builder.add_id_and_string(BINARY_INFO_ID_PROGRAM_NAME, "firmware");
+        builder.add_id_and_string(BINARY_INFO_ID_PROGRAM_VERSION_STRING, "2.2.17.f1b1b3a");
+        builder.add_id_and_string(BINARY_INFO_ID_PROGRAM_BUILD_DATE_STRING, "Dec 21 2023");
+        builder.add_id_and_string(BINARY_INFO_ID_PICO_BOARD, "pico");
+
+        let info = read_rp2040_binary_info(&builder.build()).unwrap();
+
+        assert_eq!(info.program_name.as_deref(), Some("firmware"));
+        assert_eq!(info.program_version.as_deref(), Some("2.2.17.f1b1b3a"));
+        assert_eq!(info.program_build_date.as_deref(), Some("Dec 21 2023"));
+        assert_eq!(info.pico_board.as_deref(), Some("pico"));
+        assert!(info.pins.is_empty());
+    }
+
+    #[test]
+    fn reads_strings_through_copy_to_ram_mappings() {
+        let mut builder = Rp2040FlashImageBuilder::new();
+        let source_address = builder.add_string("2.2.17.f1b1b3a");
+        builder
+            .mappings
+            .push((source_address, 0x2000_0000, 0x2000_0010));
+        builder.add_entry(
+            BINARY_INFO_TYPE_ID_AND_STRING,
+            &[BINARY_INFO_ID_PROGRAM_VERSION_STRING, 0x2000_0000],
+        );
+
+        let info = read_rp2040_binary_info(&builder.build()).unwrap();
+
+        assert_eq!(info.program_version.as_deref(), Some("2.2.17.f1b1b3a"));
+    }
+
+    #[test]
+    fn reads_pin_functions_and_names() {
+        let (spi, uart) = (1, 2); // GPIO_FUNCTION_NAMES indices
+        let mut builder = Rp2040FlashImageBuilder::new();
+        // GPIO 0 to 1 as UART
+        builder.add_entry(
+            BINARY_INFO_TYPE_PINS_WITH_FUNC,
+            &[BINARY_INFO_PINS_ENCODING_RANGE | uart << 3 | 1 << 13],
+        );
+        // GPIO 4 and 6 as SPI, with the last pin repeated into the unused slots
+        builder.add_entry(
+            BINARY_INFO_TYPE_PINS_WITH_FUNC,
+            &[BINARY_INFO_PINS_ENCODING_MULTI
+                | spi << 3
+                | 4 << 8
+                | 6 << 13
+                | 6 << 18
+                | 6 << 23
+                | 6 << 28],
+        );
+        let label_address = builder.add_string("TX|RX");
+        builder.add_entry(BINARY_INFO_TYPE_PINS_WITH_NAME, &[0b11, label_address]);
+
+        let info = read_rp2040_binary_info(&builder.build()).unwrap();
+
+        let pins: Vec<(u32, Vec<String>)> = info
+            .pins
+            .into_iter()
+            .map(|pin| (pin.pin, pin.functions))
+            .collect();
+
+        assert_eq!(
+            pins,
+            vec![
+                (0, vec!["UART".to_string(), "TX".to_string()]),
+                (1, vec!["UART".to_string(), "RX".to_string()]),
+                (4, vec!["SPI".to_string()]),
+                (6, vec!["SPI".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_entries_with_other_tags() {
+        let mut builder = Rp2040FlashImageBuilder::new();
+        builder.add_id_and_string(BINARY_INFO_ID_PROGRAM_NAME, "firmware");
+        let entry_address = builder.entry_addresses[0];
+        builder.write_bytes(entry_address + 2, &0x4D4Du16.to_le_bytes());
+
+        let info = read_rp2040_binary_info(&builder.build()).unwrap();
+
+        assert_eq!(info.program_name, None);
+    }
+
+    #[test]
+    fn returns_none_without_binary_info() {
+        assert!(read_rp2040_binary_info(&build_rp2040_uf2_blocks(4)).is_none());
+    }
+
+    #[test]
+    fn parses_program_versions() {
+        assert_eq!(
+            parse_program_version("2.2.17.f1b1b3a"),
+            Some(((2, 2, 17), Some("f1b1b3a")))
+        );
+        assert_eq!(parse_program_version("v2.2.17"), Some(((2, 2, 17), None)));
+        assert_eq!(parse_program_version("2.2"), None);
+        assert_eq!(parse_program_version("2.2.17.f1b1b3a.1"), None);
+        assert_eq!(parse_program_version("unknown"), None);
+    }
+
+    #[test]
+    fn accepts_matching_board_and_version() {
+        let board = build_board(47, "RPI_PICO", "pico", "rp2040");
+        let firmware_version = build_firmware_version("f1b1b3a");
+
+        for program_version in ["2.2.17.f1b1b3a", "2.2.17"] {
+            assert!(check_rp2040_firmware_version(
+                Some(&build_binary_info("pico", program_version)),
+                &board,
+                &firmware_version
+            )
+            .is_ok());
+        }
+    }
+
+    #[test]
+    fn matches_board_by_program_name() {
+        let board = build_board(76, "RPI_PICOW", "rpipicow", "rp2040");
+        let binary_info = Rp2040BinaryInfo {
+            pico_board: None,
+            program_name: Some("rpipicow".to_string()),
+            ..build_binary_info("", "2.2.17.f1b1b3a")
+        };
+
+        assert!(check_rp2040_firmware_version(
+            Some(&binary_info),
+            &board,
+            &build_firmware_version("f1b1b3a")
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn rejects_other_boards() {
+        let board = build_board(47, "RPI_PICO", "pico", "rp2040");
+
+        let error = check_rp2040_firmware_version(
+            Some(&build_binary_info("rak11310", "2.2.17.f1b1b3a")),
+            &board,
+            &build_firmware_version("f1b1b3a"),
+        )
+        .unwrap_err();
+
+        assert!(error.contains("rak11310"));
+    }
+
+    #[test]
+    fn rejects_missing_or_unrecognised_binary_info() {
+        let board = build_board(47, "RPI_PICO", "pico", "rp2040");
+        let firmware_version = build_firmware_version("f1b1b3a");
+        let unnamed_binary_info = Rp2040BinaryInfo {
+            pico_board: None,
+            program_name: None,
+            ..build_binary_info("", "2.2.17.f1b1b3a")
+        };
+        let unversioned_binary_info = Rp2040BinaryInfo {
+            program_version: None,
+            ..build_binary_info("pico", "")
+        };
+
+        assert!(check_rp2040_firmware_version(None, &board, &firmware_version).is_err());
+        assert!(check_rp2040_firmware_version(
+            Some(&unnamed_binary_info),
+            &board,
+            &firmware_version
+        )
+        .is_err());
+        assert!(check_rp2040_firmware_version(
+            Some(&unversioned_binary_info),
+            &board,
+            &firmware_version
+        )
+        .is_err());
+        assert!(check_rp2040_firmware_version(
+            Some(&build_binary_info("pico", "unknown")),
+            &board,
+            &firmware_version
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_version_that_only_contains_the_expected_one() {
+        let board = build_board(47, "RPI_PICO", "pico", "rp2040");
+        let firmware_version = build_firmware_version("f1b1b3a");
+
+        for program_version in ["12.2.17.f1b1b3a", "2.2.170.f1b1b3a", "2.2.17.0000000"] {
+            assert!(check_rp2040_firmware_version(
+                Some(&build_binary_info("pico", program_version)),
+                &board,
+                &firmware_version
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn plans_flash_with_binary_info_error() {
+        let board = build_board(47, "RPI_PICO", "pico", "rp2040");
+        let mut builder = Rp2040FlashImageBuilder::new();
+        builder.add_id_and_string(BINARY_INFO_ID_PICO_BOARD, "pico");
+        builder.add_id_and_string(BINARY_INFO_ID_PROGRAM_VERSION_STRING, "2.2.16.f1b1b3a");
+        let image = build_uf2_image(&builder.build());
+
+        let flash_plan = plan_rp2040_flash(
+            "firmware-pico-2.2.17.f1b1b3a.uf2".to_string(),
+            &image,
+            &board,
+            &build_firmware_version("f1b1b3a"),
+        )
+        .unwrap();
+
+        assert_eq!(flash_plan.image_size, image.len());
+        assert_eq!(flash_plan.block_count, 4);
+        assert_eq!(
+            flash_plan
+                .binary_info
+                .and_then(|binary_info| binary_info.pico_board)
+                .as_deref(),
+            Some("pico")
+        );
+        assert!(flash_plan.binary_info_error.unwrap().contains("2.2.16"));
+    }
+}
@@ -0,0 +1,108 @@
+// Security eFuse layout per chip, as documented in each chip's technical reference manual
+// https://docs.espressif.com/projects/esp-idf/en/latest/esp32/security/flash-encryption.html
+
+use espflash::{flasher::Flasher, targets::Chip};
+
+// ESP32 keeps its security fuses in eFuse block 0
+const ESP32_EFUSE_BLK0_RDATA0: u32 = 0x3FF5_A000;
+const ESP32_EFUSE_BLK0_RDATA6: u32 = 0x3FF5_A018;
+const ESP32_FLASH_CRYPT_CNT_SHIFT: u32 = 20;
+const ESP32_FLASH_CRYPT_CNT_MASK: u32 = 0x7F;
+const ESP32_ABS_DONE_MASK: u32 = 0b11 << 4; // Secure boot V1 or V2
+
+// Later chips share one layout in the eFuse "repeat data" registers, at different bases
+const EFUSE_RD_REPEAT_DATA1_OFFSET: u32 = 0x34;
+const EFUSE_RD_REPEAT_DATA2_OFFSET: u32 = 0x38;
+const SPI_BOOT_CRYPT_CNT_SHIFT: u32 = 18;
+const SPI_BOOT_CRYPT_CNT_MASK: u32 = 0x7;
+const SECURE_BOOT_EN_MASK: u32 = 1 << 20;
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EspSecurityInfo {
+    pub secure_boot_enabled: bool,
+    pub flash_encryption_enabled: bool,
+    pub flash_crypt_cnt: u32, // Encryption is on while an odd number of bits are set
+}
+
+fn get_efuse_base(chip: &Chip) -> Result<u32, String> {
+    match chip {
+        Chip::Esp32s2 => Ok(0x3F41_A000),
+        Chip::Esp32s3 => Ok(0x6000_7000),
+        Chip::Esp32c3 => Ok(0x6000_8800),
+        Chip::Esp32c6 | Chip::Esp32h2 => Ok(0x600B_0800),
+        _ => {
+            log::error!("Security eFuse layout unknown for chip {}", chip);
+            Err(format!("Security eFuse layout unknown for chip {}", chip))
+        }
+    }
+}
+
+fn read_efuse_register(flasher: &mut Flasher, address: u32) -> Result<u32, String> {
+    match flasher.connection().read_reg(address) {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            log::error!(
+                "Error while reading eFuse register 0x{:08x}: {}",
+                address,
+                e
+            );
+            Err(format!(
+                "Error while reading eFuse register 0x{:08x}: {}",
+                address, e
+            ))
+        }
+    }
+}
+
+/// Reads the secure boot and flash encryption eFuses of the connected chip
+pub fn read_esp_security_info(
+    flasher: &mut Flasher,
+    chip: &Chip,
+) -> Result<EspSecurityInfo, String> {
+    let (flash_crypt_cnt, secure_boot_enabled) = if let Chip::Esp32 = chip {
+        let rdata0 = read_efuse_register(flasher, ESP32_EFUSE_BLK0_RDATA0)?;
+        let rdata6 = read_efuse_register(flasher, ESP32_EFUSE_BLK0_RDATA6)?;
+
+        (
+            (rdata0 >> ESP32_FLASH_CRYPT_CNT_SHIFT) & ESP32_FLASH_CRYPT_CNT_MASK,
+            rdata6 & ESP32_ABS_DONE_MASK != 0,
+        )
+    } else {
+        let efuse_base = get_efuse_base(chip)?;
+        let repeat_data1 = read_efuse_register(flasher, efuse_base + EFUSE_RD_REPEAT_DATA1_OFFSET)?;
+        let repeat_data2 = read_efuse_register(flasher, efuse_base + EFUSE_RD_REPEAT_DATA2_OFFSET)?;
+
+        (
+            (repeat_data1 >> SPI_BOOT_CRYPT_CNT_SHIFT) & SPI_BOOT_CRYPT_CNT_MASK,
+            repeat_data2 & SECURE_BOOT_EN_MASK != 0,
+        )
+    };
+
+    let security_info = EspSecurityInfo {
+        secure_boot_enabled,
+        flash_encryption_enabled: flash_crypt_cnt.count_ones() % 2 == 1,
+        flash_crypt_cnt,
+    };
+
+    log::info!("Read security eFuses: {:?}", security_info);
+
+    Ok(security_info)
+}
+
+/// Refuses plaintext writes the device would reject or misinterpret
+pub fn check_plaintext_writable(security_info: &EspSecurityInfo) -> Result<(), String> {
+    if security_info.flash_encryption_enabled {
+        log::error!("Refusing to write to a device with flash encryption enabled");
+
+        return Err("Flash encryption is enabled on this device. The bootloader decrypts everything it reads from flash, so plaintext firmware would be read back as garbage and the device would no longer boot. Encrypted devices must be updated over the air or with firmware encrypted for their key.".to_string());
+    }
+
+    if security_info.secure_boot_enabled {
+        log::error!("Refusing to write to a device with secure boot enabled");
+
+        return Err("Secure boot is enabled on this device. It only boots images signed with its own key, so unsigned Meshtastic firmware would leave the device unable to boot.".to_string());
+    }
+
+    Ok(())
+}
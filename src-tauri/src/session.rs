@@ -0,0 +1,530 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use espflash::flasher::Flasher;
+
+use crate::api::boards::Board;
+use crate::backup::BackupMetadata;
+use crate::chip::{detect_esp_chip, get_esp_target, EspChipInfo};
+use crate::flasher::{
+    connect_esp32_flasher, EspConnectionConfig, EspResetStrategy, FALLBACK_ESP_BAUD_RATE,
+};
+use crate::identity::{read_esp_identity, EspDeviceIdentity};
+use crate::progress::{JobProgress, JobStage};
+use crate::security::check_plaintext_writable;
+
+const WRITE_CHUNK_SIZE: usize = 64 * 1024; // 64KB chunk size, also the cancellation granularity
+const FLASH_SECTOR_SIZE: usize = 0x1000;
+
+/// Summary of the data written to an ESP32 during a session
+#[derive(Clone, Debug)]
+pub struct EspFlashReport {
+    pub chip_info: EspChipInfo,
+    pub identity: EspDeviceIdentity,
+    pub verified: bool, // Every written region matched its MD5 on readback
+    pub baud_rate: u32,
+    pub reset_strategy: EspResetStrategy,
+    pub bytes_written: usize,
+    pub elapsed: Duration,
+    pub backup: Option<BackupMetadata>, // Set when the job took a pre-flash backup
+}
+
+impl EspFlashReport {
+    pub fn throughput_bytes_per_second(&self) -> u64 {
+        let elapsed_ms = self.elapsed.as_millis().max(1) as u64;
+        self.bytes_written as u64 * 1000 / elapsed_ms
+    }
+}
+
+/// An image to be written at a fixed flash offset
+#[derive(Clone, Debug)]
+pub struct EspImage<'a> {
+    pub name: String,
+    pub offset: u32,
+    pub data: &'a [u8],
+}
+
+/// Maps espflash's per-chunk block progress onto byte progress through the whole image
+struct EspWriteProgress<'a> {
+    job_progress: &'a mut JobProgress,
+    chunk_offset: usize,
+    chunk_length: usize,
+    image_length: usize,
+    block_total: usize,
+}
+
+impl espflash::flasher::ProgressCallbacks for EspWriteProgress<'_> {
+    fn init(&mut self, addr: u32, total: usize) {
+        log::debug!(
+            "Initializing flash progress with addr: {}, total: {}",
+            addr,
+            total
+        );
+
+        self.block_total = total;
+    }
+
+    fn update(&mut self, current: usize) {
+        let chunk_progress = self.chunk_length * current / self.block_total.max(1);
+
+        self.job_progress
+            .update(self.chunk_offset + chunk_progress, self.image_length);
+    }
+
+    fn finish(&mut self) {
+        log::debug!("Successfully flashed firmware chunk");
+    }
+}
+
+/// Errors from a dropped or corrupted packet, which reconnecting and resending usually clears
+///
+/// Errors the ROM reports for a packet it did receive, e.g. a failed flash write, aren't
+/// retried, since resending the same data won't change the outcome.
+fn is_transient_write_error(error: &espflash::error::Error) -> bool {
+    use espflash::error::{ConnectionError, Error};
+
+    matches!(
+        error,
+        Error::Connection(ConnectionError::Timeout(_))
+            | Error::Connection(ConnectionError::InvalidSerialRead)
+            | Error::Flashing(ConnectionError::Timeout(_))
+            | Error::Flashing(ConnectionError::InvalidSerialRead)
+    )
+}
+
+/// A single connection to an ESP32 bootloader, shared by every operation in a flash job
+///
+/// The device is reset and synced once on connect, the stub is loaded once, and the device is
+/// only rebooted when `reset` is called at the end of the job. A session dropped without
+/// `reset`, e.g. on cancellation, leaves the device waiting in the bootloader.
+pub struct EspFlashSession {
+    temp_directory: PathBuf, // Scratch space for flash readback
+    upload_port: String,
+    connection_config: EspConnectionConfig,
+    flasher: Option<Flasher>, // Only empty while reconnecting
+    chip_info: EspChipInfo,
+    identity: EspDeviceIdentity,
+    baud_rate: u32,
+    reset_strategy: EspResetStrategy,
+    bytes_written: usize,
+    verified_bytes: usize,
+    write_duration: Duration,
+}
+
+impl EspFlashSession {
+    pub async fn connect(
+        temp_directory: PathBuf,
+        upload_port: &String,
+        board: &Board,
+        required_flash_size: u32,
+        connection_config: &EspConnectionConfig,
+        progress: &mut JobProgress,
+    ) -> Result<Self, String> {
+        progress.start_stage(JobStage::Connect);
+
+        let (mut flasher, baud_rate, reset_strategy) =
+            connect_esp32_flasher(upload_port, connection_config).await?;
+
+        // Nothing is written on a session whose chip doesn't match the selected board
+        let chip_info = detect_esp_chip(&mut flasher, board, required_flash_size)?;
+
+        // Read up front so every job records which device it touched
+        let chip = get_esp_target(&board.architecture)?.chip;
+        let identity = read_esp_identity(&mut flasher, &chip, &chip_info)?;
+
+        log::info!(
+            "Opened flash session on port {} at {} baud with reset strategy {:?}",
+            upload_port,
+            baud_rate,
+            reset_strategy
+        );
+
+        // Reconnects start from the strategy that is known to work
+        let connection_config = EspConnectionConfig {
+            reset_strategy: Some(reset_strategy),
+            ..connection_config.clone()
+        };
+
+        Ok(EspFlashSession {
+            temp_directory,
+            upload_port: upload_port.clone(),
+            connection_config,
+            flasher: Some(flasher),
+            chip_info,
+            identity,
+            baud_rate,
+            reset_strategy,
+            bytes_written: 0,
+            verified_bytes: 0,
+            write_duration: Duration::ZERO,
+        })
+    }
+
+    pub fn temp_directory(&self) -> &PathBuf {
+        &self.temp_directory
+    }
+
+    pub fn upload_port(&self) -> &String {
+        &self.upload_port
+    }
+
+    pub fn chip_info(&self) -> &EspChipInfo {
+        &self.chip_info
+    }
+
+    pub fn identity(&self) -> &EspDeviceIdentity {
+        &self.identity
+    }
+
+    pub fn flasher(&mut self) -> Result<&mut Flasher, String> {
+        match self.flasher.as_mut() {
+            Some(flasher) => Ok(flasher),
+            None => {
+                log::error!(
+                    "Flash session on port {} lost its connection",
+                    self.upload_port
+                );
+                Err(format!(
+                    "Flash session on port {} lost its connection",
+                    self.upload_port
+                ))
+            }
+        }
+    }
+
+    /// Drops the connection and opens a new one, leaving the device in the bootloader
+    async fn reconnect(&mut self) -> Result<(), String> {
+        log::info!("Reconnecting flash session on port {}", self.upload_port);
+
+        // The old port has to be closed before it can be reopened
+        self.flasher = None;
+
+        let (flasher, baud_rate, reset_strategy) =
+            connect_esp32_flasher(&self.upload_port, &self.connection_config).await?;
+
+        self.flasher = Some(flasher);
+        self.baud_rate = baud_rate;
+        self.reset_strategy = reset_strategy;
+
+        Ok(())
+    }
+
+    /// Writes each image in turn, optionally verifying each one by MD5 readback
+    ///
+    /// An image lost or corrupted at a fast baud rate is written again from the start at 115200.
+    pub async fn write_images(
+        &mut self,
+        images: &[EspImage<'_>],
+        verify: bool,
+        progress: &mut JobProgress,
+    ) -> Result<(), String> {
+        check_plaintext_writable(&self.chip_info.security)?;
+
+        progress.plan_images(images.iter().map(|image| image.data.len()).collect());
+
+        for (index, image) in images.iter().enumerate() {
+            progress.check_cancelled()?;
+
+            match self
+                .write_and_verify_image(index, images.len(), image, verify, progress)
+                .await
+            {
+                Ok(_) => (),
+                Err(e) if self.baud_rate != FALLBACK_ESP_BAUD_RATE && !progress.is_cancelled() => {
+                    log::warn!(
+                        "Error while writing {} at {} baud, retrying at {} baud: {}",
+                        image.name,
+                        self.baud_rate,
+                        FALLBACK_ESP_BAUD_RATE,
+                        e
+                    );
+
+                    self.change_baud_rate(FALLBACK_ESP_BAUD_RATE)?;
+                    self.write_and_verify_image(index, images.len(), image, verify, progress)
+                        .await?;
+                }
+                Err(e) => return Err(e),
+            };
+
+            log::info!(
+                "Successfully flashed {} at 0x{:08x}",
+                image.name,
+                image.offset
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn write_and_verify_image(
+        &mut self,
+        index: usize,
+        image_count: usize,
+        image: &EspImage<'_>,
+        verify: bool,
+        progress: &mut JobProgress,
+    ) -> Result<(), String> {
+        progress.start_stage(JobStage::Write {
+            image_index: index + 1,
+            image_count,
+            image_name: image.name.clone(),
+        });
+
+        let write_duration = self.write_image(image, progress).await?;
+
+        if verify {
+            progress.start_stage(JobStage::Verify {
+                image_index: index + 1,
+                image_count,
+                image_name: image.name.clone(),
+            });
+
+            self.verify_region(image.offset, image.data)?;
+            self.verified_bytes += image.data.len();
+
+            progress.update(image.data.len(), image.data.len());
+        }
+
+        // Only counted once the image made it, so a retried image isn't reported twice
+        self.bytes_written += image.data.len();
+        self.write_duration += write_duration;
+
+        Ok(())
+    }
+
+    fn change_baud_rate(&mut self, baud_rate: u32) -> Result<(), String> {
+        match self.flasher()?.change_baud(baud_rate) {
+            Ok(_) => (),
+            Err(e) => {
+                log::error!(
+                    "Error while changing baud rate on port {} to {}: {}",
+                    self.upload_port,
+                    baud_rate,
+                    e
+                );
+
+                return Err(format!(
+                    "Error while changing baud rate on port {} to {}: {}",
+                    self.upload_port, baud_rate, e
+                ));
+            }
+        };
+
+        // Any later reconnect stays at the slower rate
+        self.baud_rate = baud_rate;
+        self.connection_config.baud_rate = baud_rate;
+
+        Ok(())
+    }
+
+    async fn write_image(
+        &mut self,
+        image: &EspImage<'_>,
+        progress: &mut JobProgress,
+    ) -> Result<Duration, String> {
+        log::info!(
+            "Writing {} byte image {} at 0x{:08x} on port {}",
+            image.data.len(),
+            image.name,
+            image.offset,
+            self.upload_port
+        );
+
+        let write_start = Instant::now();
+        let mut current_flash_offset = image.offset;
+        let mut chunk_offset = 0;
+
+        for data_chunk in image.data.chunks(WRITE_CHUNK_SIZE) {
+            if progress.is_cancelled() {
+                self.erase_partial_image(image, chunk_offset)?;
+                progress.check_cancelled()?;
+            }
+
+            log::debug!(
+                "Flashing {} byte chunk at address {}",
+                data_chunk.len(),
+                current_flash_offset
+            );
+
+            let mut retries = 0;
+
+            // Each attempt rewrites the whole chunk, since the loader erases its region first
+            loop {
+                let mut write_progress = EspWriteProgress {
+                    job_progress: &mut *progress,
+                    chunk_offset,
+                    chunk_length: data_chunk.len(),
+                    image_length: image.data.len(),
+                    block_total: 0,
+                };
+
+                // Reboot is deferred to the end of the session
+                match self.flasher()?.write_bin_to_flash(
+                    current_flash_offset,
+                    data_chunk,
+                    Some(&mut write_progress),
+                    false,
+                ) {
+                    Ok(_) => break,
+                    Err(e)
+                        if is_transient_write_error(&e)
+                            && retries < self.connection_config.max_write_retries =>
+                    {
+                        retries += 1;
+
+                        log::warn!(
+                            "Transient error while writing chunk at 0x{:08x}, retrying ({}/{}): {}",
+                            current_flash_offset,
+                            retries,
+                            self.connection_config.max_write_retries,
+                            e
+                        );
+
+                        self.reconnect().await?;
+                    }
+                    Err(e) => {
+                        log::error!("Error while writing data buffer to board: {}", e);
+                        return Err(format!("Error while writing data buffer to board: {}", e));
+                    }
+                };
+            }
+
+            current_flash_offset += data_chunk.len() as u32;
+            chunk_offset += data_chunk.len();
+        }
+
+        log::info!("Finished writing binary data to board");
+
+        Ok(write_start.elapsed())
+    }
+
+    /// Erases the part of an image written before cancellation so the bootloader never boots it
+    fn erase_partial_image(
+        &mut self,
+        image: &EspImage<'_>,
+        bytes_written: usize,
+    ) -> Result<(), String> {
+        if bytes_written == 0 {
+            return Ok(());
+        }
+
+        let erase_size = bytes_written.div_ceil(FLASH_SECTOR_SIZE) * FLASH_SECTOR_SIZE;
+
+        log::info!(
+            "Erasing partially written {} at 0x{:08x}..0x{:08x}",
+            image.name,
+            image.offset,
+            image.offset + erase_size as u32
+        );
+
+        match self
+            .flasher()?
+            .erase_region(image.offset, erase_size as u32)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::error!(
+                    "Error while erasing partially written {} on port {}: {}",
+                    image.name,
+                    self.upload_port,
+                    e
+                );
+
+                Err(format!(
+                    "Error while erasing partially written {} on port {}: {}",
+                    image.name, self.upload_port, e
+                ))
+            }
+        }
+    }
+
+    /// Compares the MD5 the loader computes over a flash region with the local image
+    fn verify_region(&mut self, flash_offset: u32, binary_data: &[u8]) -> Result<(), String> {
+        let region_end = flash_offset + binary_data.len() as u32;
+
+        log::info!(
+            "Verifying flash region 0x{:08x}..0x{:08x}",
+            flash_offset,
+            region_end
+        );
+
+        let expected_checksum = u128::from_be_bytes(md5::compute(binary_data).0);
+
+        let device_checksum = match self
+            .flasher()?
+            .checksum_md5(flash_offset, binary_data.len() as u32)
+        {
+            Ok(checksum) => checksum,
+            Err(e) => {
+                log::error!(
+                    "Error while reading checksum of flash region 0x{:08x}..0x{:08x}: {}",
+                    flash_offset,
+                    region_end,
+                    e
+                );
+
+                return Err(format!(
+                    "Error while reading checksum of flash region 0x{:08x}..0x{:08x}: {}",
+                    flash_offset, region_end, e
+                ));
+            }
+        };
+
+        if device_checksum != expected_checksum {
+            log::error!(
+                "Verification failed for flash region 0x{:08x}..0x{:08x}: expected MD5 {:032x}, device reported {:032x}",
+                flash_offset,
+                region_end,
+                expected_checksum,
+                device_checksum
+            );
+
+            return Err(format!(
+                "Verification failed for flash region 0x{:08x}..0x{:08x}: expected MD5 {:032x}, device reported {:032x}",
+                flash_offset, region_end, expected_checksum, device_checksum
+            ));
+        }
+
+        log::info!(
+            "Verified flash region 0x{:08x}..0x{:08x}",
+            flash_offset,
+            region_end
+        );
+
+        Ok(())
+    }
+
+    /// Hard resets the device out of the bootloader, ending the session
+    pub fn reset(mut self, progress: &mut JobProgress) -> Result<EspFlashReport, String> {
+        progress.start_stage(JobStage::Reboot);
+
+        match self.flasher()?.connection().reset() {
+            Ok(_) => (),
+            Err(e) => {
+                log::error!(
+                    "Error while resetting device on port {}: {}",
+                    self.upload_port,
+                    e
+                );
+
+                return Err(format!(
+                    "Error while resetting device on port {}: {}",
+                    self.upload_port, e
+                ));
+            }
+        };
+
+        log::info!("Closed flash session on port {}", self.upload_port);
+
+        Ok(EspFlashReport {
+            chip_info: self.chip_info,
+            identity: self.identity,
+            verified: self.bytes_written > 0 && self.verified_bytes == self.bytes_written,
+            baud_rate: self.baud_rate,
+            reset_strategy: self.reset_strategy,
+            bytes_written: self.bytes_written,
+            elapsed: self.write_duration,
+            backup: None,
+        })
+    }
+}
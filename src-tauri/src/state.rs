@@ -1,8 +1,10 @@
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
 use tokio::sync::Mutex;
 
-use crate::api::{boards::ListBoardsResponse, firmware::ListFirmwareResponse};
+use meshtastic_desktop_flasher::api::{boards::ListBoardsResponse, firmware::ListFirmwareResponse};
 
 pub type FirmwareReleasesStateInner = Arc<Mutex<ListFirmwareResponse>>;
 
@@ -17,3 +19,21 @@ pub type BoardsStateInner = Arc<Mutex<ListBoardsResponse>>;
 pub struct BoardsState {
     pub inner: BoardsStateInner,
 }
+
+/// Last baud rate that successfully flashed each port and board, keyed by `{port}:{hw_model_slug}`
+///
+/// Backed by the `.baud_rates.dat` store, so entries survive restarts.
+pub type BaudRateStateInner = Arc<Mutex<HashMap<String, u32>>>;
+
+#[derive(Debug, Default)]
+pub struct BaudRateState {
+    pub inner: BaudRateStateInner,
+}
+
+/// Cancellation flags of the jobs currently holding a port, keyed by upload port
+pub type FlashJobsStateInner = Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>;
+
+#[derive(Debug, Default)]
+pub struct FlashJobsState {
+    pub inner: FlashJobsStateInner,
+}
@@ -0,0 +1,304 @@
+// Builders for the fixtures shared by the unit tests
+
+use std::sync::Arc;
+
+use crate::api::boards::Board;
+use crate::backup::BackupMetadata;
+use crate::chip::{ESP_IMAGE_CHIP_ID_OFFSET, ESP_IMAGE_MAGIC};
+use crate::flasher::FirmwareVersion;
+use crate::partitions::{
+    APP_SUBTYPE_OTA_0, APP_SUBTYPE_OTA_1, DATA_SUBTYPE_NVS, DATA_SUBTYPE_SPIFFS,
+    PARTITION_ENTRY_MAGIC, PARTITION_MD5_MAGIC, PARTITION_TYPE_APP, PARTITION_TYPE_DATA,
+};
+use crate::progress::{CollectingProgressSink, JobProgress, JobStage};
+use crate::rp2040::{
+    BINARY_INFO_MARKER_END, BINARY_INFO_MARKER_START, BINARY_INFO_TAG_RASPBERRY_PI,
+    BINARY_INFO_TYPE_ID_AND_STRING, RP2040_FAMILY_ID, RP2040_FLASH_START, RP2040_UF2_PAYLOAD_SIZE,
+};
+use crate::uf2::{
+    Uf2Block, Uf2BootloaderInfo, UF2_BLOCK_SIZE, UF2_FLAG_FAMILY_ID_PRESENT, UF2_MAGIC_END,
+    UF2_MAGIC_START0, UF2_MAGIC_START1,
+};
+
+/// Single binary partition table entry
+pub fn build_partition_entry(
+    label: &str,
+    partition_type: u8,
+    subtype: u8,
+    offset: u32,
+    size: u32,
+) -> Vec<u8> {
+    let mut entry = PARTITION_ENTRY_MAGIC.to_vec();
+    entry.extend_from_slice(&[partition_type, subtype]);
+    entry.extend_from_slice(&offset.to_le_bytes());
+    entry.extend_from_slice(&size.to_le_bytes());
+
+    let mut label_bytes = [0u8; 16];
+    label_bytes[..label.len()].copy_from_slice(label.as_bytes());
+    entry.extend_from_slice(&label_bytes);
+
+    entry.extend_from_slice(&[0u8; 4]); // Flags
+    entry
+}
+
+/// MD5 entry that terminates a partition table
+pub fn build_partition_md5_entry() -> Vec<u8> {
+    let mut entry = PARTITION_MD5_MAGIC.to_vec();
+    entry.extend_from_slice(&[0xFF; 14]);
+    entry.extend_from_slice(&[0x5A; 16]);
+    entry
+}
+
+/// Meshtastic's 4MB ESP32 layout
+pub fn build_meshtastic_partition_table() -> Vec<u8> {
+    [
+        build_partition_entry("nvs", PARTITION_TYPE_DATA, DATA_SUBTYPE_NVS, 0x9000, 0x5000),
+        build_partition_entry("otadata", PARTITION_TYPE_DATA, 0x00, 0xE000, 0x2000),
+        build_partition_entry(
+            "app",
+            PARTITION_TYPE_APP,
+            APP_SUBTYPE_OTA_0,
+            0x10000,
+            0x250000,
+        ),
+        build_partition_entry(
+            "flashApp",
+            PARTITION_TYPE_APP,
+            APP_SUBTYPE_OTA_1,
+            0x260000,
+            0xA0000,
+        ),
+        build_partition_entry(
+            "spiffs",
+            PARTITION_TYPE_DATA,
+            DATA_SUBTYPE_SPIFFS,
+            0x300000,
+            0x100000,
+        ),
+    ]
+    .concat()
+}
+
+/// ESP-IDF image with its header at the given offset, built for the given chip ID
+pub fn build_esp_image(offset: usize, image_chip_id: u16) -> Vec<u8> {
+    let mut image = vec![0xFF; offset + 0x100];
+    image[offset] = ESP_IMAGE_MAGIC;
+    image[offset + ESP_IMAGE_CHIP_ID_OFFSET..offset + ESP_IMAGE_CHIP_ID_OFFSET + 2]
+        .copy_from_slice(&image_chip_id.to_le_bytes());
+    image
+}
+
+pub fn build_board(hw_model: u32, slug: &str, target: &str, architecture: &str) -> Board {
+    Board {
+        hw_model,
+        hw_model_slug: slug.to_string(),
+        platformio_target: target.to_string(),
+        architecture: architecture.to_string(),
+        ..Default::default()
+    }
+}
+
+/// A few supported boards of each architecture
+pub fn build_boards() -> Vec<Board> {
+    vec![
+        build_board(9, "RAK4631", "rak4631", "nrf52840"),
+        build_board(7, "T_ECHO", "t-echo", "nrf52840"),
+        build_board(47, "RPI_PICO", "pico", "rp2040"),
+        build_board(4, "TBEAM", "tbeam", "esp32"),
+    ]
+}
+
+/// Job progress for a fake port, reporting into a sink the test can inspect
+pub fn build_job_progress() -> (Arc<CollectingProgressSink>, JobProgress) {
+    let sink = Arc::new(CollectingProgressSink::default());
+    let progress = JobProgress::new(sink.clone(), &"/dev/ttyUSB0".to_string());
+
+    (sink, progress)
+}
+
+/// Block `block_number` of an RP2040 image, writing one flash page
+pub fn build_rp2040_uf2_block(block_number: u32, block_count: u32) -> Uf2Block {
+    Uf2Block {
+        flags: UF2_FLAG_FAMILY_ID_PRESENT,
+        target_address: RP2040_FLASH_START + block_number * RP2040_UF2_PAYLOAD_SIZE as u32,
+        block_number,
+        block_count,
+        family_id: Some(RP2040_FAMILY_ID),
+        data: vec![0xA5; RP2040_UF2_PAYLOAD_SIZE],
+    }
+}
+
+/// An RP2040 image of `block_count` consecutive flash pages
+pub fn build_rp2040_uf2_blocks(block_count: u32) -> Vec<Uf2Block> {
+    (0..block_count)
+        .map(|block_number| build_rp2040_uf2_block(block_number, block_count))
+        .collect()
+}
+
+/// Serializes blocks into a UF2 image, as written by uf2conv.py
+pub fn build_uf2_image(blocks: &[Uf2Block]) -> Vec<u8> {
+    let mut image = Vec::with_capacity(blocks.len() * UF2_BLOCK_SIZE);
+
+    for block in blocks {
+        let mut bytes = vec![0; UF2_BLOCK_SIZE];
+        let header = [
+            UF2_MAGIC_START0,
+            UF2_MAGIC_START1,
+            block.flags,
+            block.target_address,
+            block.data.len() as u32,
+            block.block_number,
+            block.block_count,
+            block.family_id.unwrap_or(0),
+        ];
+
+        for (index, word) in header.iter().enumerate() {
+            bytes[index * 4..index * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        bytes[32..32 + block.data.len()].copy_from_slice(&block.data);
+        bytes[UF2_BLOCK_SIZE - 4..].copy_from_slice(&UF2_MAGIC_END.to_le_bytes());
+        image.extend(bytes);
+    }
+
+    image
+}
+
+// Binary info laid out the way the Pico SDK links it, 0x100 bytes into flash
+const BINARY_INFO_HEADER_ADDRESS: u32 = RP2040_FLASH_START + 0x100;
+const BINARY_INFO_ENTRY_TABLE_ADDRESS: u32 = RP2040_FLASH_START + 0x200;
+const BINARY_INFO_ENTRIES_ADDRESS: u32 = RP2040_FLASH_START + 0x240;
+const BINARY_INFO_STRINGS_ADDRESS: u32 = RP2040_FLASH_START + 0x300;
+const BINARY_INFO_MAPPING_TABLE_ADDRESS: u32 = RP2040_FLASH_START + 0x3E0;
+const RP2040_FLASH_IMAGE_SIZE: usize = 0x400;
+
+/// Lays out an RP2040 flash image with a binary info block, entry by entry
+pub struct Rp2040FlashImageBuilder {
+    flash: Vec<u8>,
+    pub entry_addresses: Vec<u32>,
+    next_entry_address: u32,
+    next_string_address: u32,
+    pub mappings: Vec<(u32, u32, u32)>, // Source start, destination start, destination end
+}
+
+impl Rp2040FlashImageBuilder {
+    pub fn new() -> Self {
+        Rp2040FlashImageBuilder {
+            flash: vec![0; RP2040_FLASH_IMAGE_SIZE],
+            entry_addresses: Vec::new(),
+            next_entry_address: BINARY_INFO_ENTRIES_ADDRESS,
+            next_string_address: BINARY_INFO_STRINGS_ADDRESS,
+            mappings: Vec::new(),
+        }
+    }
+
+    pub fn write_bytes(&mut self, address: u32, bytes: &[u8]) {
+        let offset = (address - RP2040_FLASH_START) as usize;
+        self.flash[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn write_u32(&mut self, address: u32, value: u32) {
+        self.write_bytes(address, &value.to_le_bytes());
+    }
+
+    pub fn add_string(&mut self, value: &str) -> u32 {
+        let address = self.next_string_address;
+        self.write_bytes(address, value.as_bytes());
+        self.next_string_address += value.len() as u32 + 1;
+        address
+    }
+
+    pub fn add_entry(&mut self, entry_type: u16, words: &[u32]) {
+        let address = self.next_entry_address;
+        self.write_bytes(address, &entry_type.to_le_bytes());
+        self.write_bytes(address + 2, &BINARY_INFO_TAG_RASPBERRY_PI.to_le_bytes());
+
+        for (index, word) in words.iter().enumerate() {
+            self.write_u32(address + 4 + index as u32 * 4, *word);
+        }
+
+        self.entry_addresses.push(address);
+        self.next_entry_address += 4 + words.len() as u32 * 4;
+    }
+
+    pub fn add_id_and_string(&mut self, id: u32, value: &str) {
+        let string_address = self.add_string(value);
+        self.add_entry(BINARY_INFO_TYPE_ID_AND_STRING, &[id, string_address]);
+    }
+
+    pub fn build(mut self) -> Vec<Uf2Block> {
+        let entries_end = BINARY_INFO_ENTRY_TABLE_ADDRESS + self.entry_addresses.len() as u32 * 4;
+
+        self.write_u32(BINARY_INFO_HEADER_ADDRESS, BINARY_INFO_MARKER_START);
+        self.write_u32(
+            BINARY_INFO_HEADER_ADDRESS + 4,
+            BINARY_INFO_ENTRY_TABLE_ADDRESS,
+        );
+        self.write_u32(BINARY_INFO_HEADER_ADDRESS + 8, entries_end);
+        self.write_u32(
+            BINARY_INFO_HEADER_ADDRESS + 12,
+            BINARY_INFO_MAPPING_TABLE_ADDRESS,
+        );
+        self.write_u32(BINARY_INFO_HEADER_ADDRESS + 16, BINARY_INFO_MARKER_END);
+
+        for (index, address) in self.entry_addresses.clone().into_iter().enumerate() {
+            self.write_u32(BINARY_INFO_ENTRY_TABLE_ADDRESS + index as u32 * 4, address);
+        }
+
+        for (index, (source, start, end)) in self.mappings.clone().into_iter().enumerate() {
+            let mapping_address = BINARY_INFO_MAPPING_TABLE_ADDRESS + index as u32 * 12;
+            self.write_u32(mapping_address, source);
+            self.write_u32(mapping_address + 4, start);
+            self.write_u32(mapping_address + 8, end);
+        }
+
+        let block_count = (RP2040_FLASH_IMAGE_SIZE / RP2040_UF2_PAYLOAD_SIZE) as u32;
+
+        self.flash
+            .chunks(RP2040_UF2_PAYLOAD_SIZE)
+            .enumerate()
+            .map(|(index, data)| Uf2Block {
+                data: data.to_vec(),
+                ..build_rp2040_uf2_block(index as u32, block_count)
+            })
+            .collect()
+    }
+}
+
+pub fn build_firmware_version(version_hash: &str) -> FirmwareVersion {
+    FirmwareVersion {
+        major_version: 2,
+        minor_version: 2,
+        patch_version: 17,
+        version_hash: version_hash.to_string(),
+    }
+}
+
+/// Metadata of a UF2 backup taken from a RAK4631 whose bootloader reported `board_id`
+pub fn build_uf2_backup_metadata(board_id: Option<&str>) -> BackupMetadata {
+    BackupMetadata {
+        file_name: "backup-1700000000-RAK4631.uf2".to_string(),
+        created_at: 1_700_000_000,
+        hw_model_slug: "RAK4631".to_string(),
+        chip: "nrf52840".to_string(),
+        mac_address: String::new(),
+        flash_size: "796KB".to_string(),
+        flash_size_bytes: 815_104,
+        uf2_bootloader: Some(build_uf2_bootloader_info(board_id)),
+    }
+}
+
+pub fn build_uf2_bootloader_info(board_id: Option<&str>) -> Uf2BootloaderInfo {
+    Uf2BootloaderInfo {
+        board_id: board_id.map(|board_id| board_id.to_string()),
+        ..Default::default()
+    }
+}
+
+pub fn build_write_stage(image_index: usize, image_count: usize) -> JobStage {
+    JobStage::Write {
+        image_index,
+        image_count,
+        image_name: format!("image {}", image_index),
+    }
+}
@@ -0,0 +1,1057 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::api::boards::Board;
+use crate::flasher::get_port_by_name;
+use crate::progress::{JobProgress, JobStage};
+
+// Opening the CDC port at 1200 baud and dropping DTR asks the running firmware to reboot into
+// its UF2 bootloader, as the Arduino IDE does for nRF52 and RP2040 boards
+const BOOTLOADER_TOUCH_BAUD_RATE: u32 = 1200;
+const BOOTLOADER_TOUCH_HOLD_TIME: Duration = Duration::from_millis(100);
+const UF2_DRIVE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const UF2_DRIVE_MOUNT_TIMEOUT: Duration = Duration::from_secs(20);
+pub const UF2_DRIVE_UNMOUNT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const FIRMWARE_PORT_TIMEOUT: Duration = Duration::from_secs(30);
+const UF2_COPY_CHUNK_SIZE: usize = 64 * 1024; // 64KB chunk size
+
+// Vendor IDs Meshtastic firmware enumerates with, used when the firmware's own port is unknown
+const ADAFRUIT_USB_VID: u16 = 0x239A; // Adafruit nRF52 core, also used by RAK boards
+const RASPBERRY_PI_USB_VID: u16 = 0x2E8A;
+
+/// Present in the root of every UF2 bootloader drive
+pub const UF2_INFO_FILE_NAME: &str = "INFO_UF2.TXT";
+
+// UF2 block layout: https://github.com/microsoft/uf2#file-format
+pub(crate) const UF2_BLOCK_SIZE: usize = 512;
+pub(crate) const UF2_MAGIC_START0: u32 = 0x0A32_4655; // "UF2\n"
+pub(crate) const UF2_MAGIC_START1: u32 = 0x9E5D_5157;
+pub(crate) const UF2_MAGIC_END: u32 = 0x0AB1_6F30;
+const UF2_MAX_PAYLOAD_SIZE: usize = 476;
+pub const UF2_FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+pub const UF2_FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+
+/// Decodes the octal escapes /proc/mounts uses for spaces, tabs and newlines in mount points
+#[cfg(target_os = "linux")]
+fn unescape_mount_point(mount_point: &str) -> String {
+    let mut unescaped = String::with_capacity(mount_point.len());
+    let mut chars = mount_point.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let escape: String = chars.by_ref().take(3).collect();
+
+            match u8::from_str_radix(&escape, 8) {
+                Ok(byte) => unescaped.push(byte as char),
+                Err(_) => {
+                    unescaped.push(c);
+                    unescaped.push_str(&escape);
+                }
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+
+    unescaped
+}
+
+#[cfg(target_os = "linux")]
+fn get_mount_points() -> Result<Vec<PathBuf>, String> {
+    let mounts = match std::fs::read_to_string("/proc/mounts") {
+        Ok(mounts) => mounts,
+        Err(e) => {
+            log::error!("Error while reading /proc/mounts: {}", e);
+            return Err(format!("Error while reading /proc/mounts: {}", e));
+        }
+    };
+
+    Ok(mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(|mount_point| PathBuf::from(unescape_mount_point(mount_point)))
+        .collect())
+}
+
+#[cfg(target_os = "macos")]
+fn get_mount_points() -> Result<Vec<PathBuf>, String> {
+    let volumes = match std::fs::read_dir("/Volumes") {
+        Ok(volumes) => volumes,
+        Err(e) => {
+            log::error!("Error while listing /Volumes: {}", e);
+            return Err(format!("Error while listing /Volumes: {}", e));
+        }
+    };
+
+    Ok(volumes
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect())
+}
+
+#[cfg(target_os = "windows")]
+fn get_mount_points() -> Result<Vec<PathBuf>, String> {
+    Ok(('A'..='Z')
+        .map(|letter| PathBuf::from(format!("{}:\\", letter)))
+        .filter(|drive| drive.exists())
+        .collect())
+}
+
+/// Mounted volumes that look like a UF2 bootloader drive
+pub fn list_uf2_drive_paths() -> Result<Vec<PathBuf>, String> {
+    Ok(get_mount_points()?
+        .into_iter()
+        .filter(|mount_point| mount_point.join(UF2_INFO_FILE_NAME).is_file())
+        .collect())
+}
+
+/// Bootloader details from a drive's INFO_UF2.TXT
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Uf2BootloaderInfo {
+    pub bootloader_version: Option<String>, // e.g. 0.6.1, without a leading "v"
+    pub model: Option<String>,
+    pub board_id: Option<String>,
+    pub soft_device: Option<String>, // nRF52 only, e.g. "S140 version 6.1.1"
+    pub date: Option<String>,
+}
+
+/// Parses INFO_UF2.TXT, which starts with a "UF2 Bootloader <version> ..." line followed by
+/// "Key: value" lines
+pub fn parse_uf2_bootloader_info(contents: &str) -> Uf2BootloaderInfo {
+    let mut info = Uf2BootloaderInfo::default();
+
+    for line in contents.lines().map(|line| line.trim()) {
+        if let Some(version_line) = line.strip_prefix("UF2 Bootloader") {
+            info.bootloader_version = version_line
+                .split_whitespace()
+                .next()
+                .map(|version| version.trim_start_matches('v').to_string());
+
+            continue;
+        }
+
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim().to_string()),
+            None => continue,
+        };
+
+        match key {
+            "Model" => info.model = Some(value),
+            "Board-ID" => info.board_id = Some(value),
+            "SoftDevice" => info.soft_device = Some(value),
+            "Date" => info.date = Some(value),
+            _ => (),
+        }
+    }
+
+    info
+}
+
+pub fn read_uf2_bootloader_info(drive_path: &Path) -> Result<Uf2BootloaderInfo, String> {
+    let info_file_path = drive_path.join(UF2_INFO_FILE_NAME);
+
+    match std::fs::read_to_string(&info_file_path) {
+        Ok(contents) => Ok(parse_uf2_bootloader_info(&contents)),
+        Err(e) => {
+            log::error!(
+                "Error while reading UF2 bootloader info at {}: {}",
+                info_file_path.display(),
+                e
+            );
+
+            Err(format!(
+                "Error while reading UF2 bootloader info at {}: {}",
+                info_file_path.display(),
+                e
+            ))
+        }
+    }
+}
+
+/// A mounted UF2 bootloader drive and the boards it could belong to
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Uf2Drive {
+    pub path: PathBuf,
+    pub info: Uf2BootloaderInfo,
+    pub matching_hw_models: Vec<u32>,
+}
+
+fn normalize_board_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Boards whose target or slug is named in the bootloader's Board-ID or Model, falling back to
+/// every board of the bootloader's architecture
+pub fn get_matching_boards<'a>(info: &Uf2BootloaderInfo, boards: &'a [Board]) -> Vec<&'a Board> {
+    let board_id = info.board_id.clone().unwrap_or_default();
+
+    let architecture = if board_id.starts_with("RPI-RP2") {
+        "rp2040"
+    } else if board_id.to_lowercase().starts_with("nrf52") || info.soft_device.is_some() {
+        "nrf52"
+    } else {
+        return Vec::new();
+    };
+
+    let architecture_boards: Vec<&Board> = boards
+        .iter()
+        .filter(|board| board.architecture.contains(architecture))
+        .collect();
+
+    let bootloader_names = [
+        normalize_board_name(&board_id),
+        normalize_board_name(&info.model.clone().unwrap_or_default()),
+    ];
+
+    let named_boards: Vec<&Board> = architecture_boards
+        .iter()
+        .filter(|board| {
+            [&board.platformio_target, &board.hw_model_slug]
+                .iter()
+                .map(|name| normalize_board_name(name))
+                .any(|name| {
+                    !name.is_empty()
+                        && bootloader_names
+                            .iter()
+                            .any(|bootloader_name| bootloader_name.contains(&name))
+                })
+        })
+        .copied()
+        .collect();
+
+    if named_boards.is_empty() {
+        architecture_boards
+    } else {
+        named_boards
+    }
+}
+
+/// Every mounted UF2 bootloader drive, matched against the supported boards
+pub fn list_uf2_drives(boards: &[Board]) -> Result<Vec<Uf2Drive>, String> {
+    let mut uf2_drives = Vec::new();
+
+    for drive_path in list_uf2_drive_paths()? {
+        // One drive that can't be read, e.g. while it is unmounting, shouldn't hide the others
+        let info = match read_uf2_bootloader_info(&drive_path) {
+            Ok(info) => info,
+            Err(e) => {
+                log::warn!("Skipping UF2 drive {}: {}", drive_path.display(), e);
+                continue;
+            }
+        };
+
+        let matching_hw_models = get_matching_boards(&info, boards)
+            .iter()
+            .map(|board| board.hw_model)
+            .collect();
+
+        uf2_drives.push(Uf2Drive {
+            path: drive_path,
+            info,
+            matching_hw_models,
+        });
+    }
+
+    log::info!("Found UF2 drives: {:?}", uf2_drives);
+
+    Ok(uf2_drives)
+}
+
+/// USB IDs of the serial port the firmware comes back on after a UF2 flash
+#[derive(Clone, Debug)]
+pub struct FirmwareUsbIds {
+    pub vid: u16,
+    pub pid: Option<u16>, // Only known when flashing was started from the firmware's own port
+}
+
+/// Reads the USB IDs of `upload_port` while it is still the firmware's port, falling back to the
+/// architecture's vendor ID when a drive was selected directly
+pub fn get_firmware_usb_ids(upload_port: &String, board: &Board) -> Result<FirmwareUsbIds, String> {
+    if !Path::new(upload_port).is_dir() {
+        if let Ok(serialport::SerialPortInfo {
+            port_type: serialport::SerialPortType::UsbPort(usb_port_info),
+            ..
+        }) = get_port_by_name(upload_port)
+        {
+            return Ok(FirmwareUsbIds {
+                vid: usb_port_info.vid,
+                pid: Some(usb_port_info.pid),
+            });
+        }
+    }
+
+    let vid = if board.architecture.contains("nrf") {
+        ADAFRUIT_USB_VID
+    } else if board.architecture.contains("rp2040") {
+        RASPBERRY_PI_USB_VID
+    } else {
+        log::error!("No UF2 bootloader for architecture {}", board.architecture);
+        return Err(format!(
+            "No UF2 bootloader for architecture {}",
+            board.architecture
+        ));
+    };
+
+    Ok(FirmwareUsbIds { vid, pid: None })
+}
+
+/// Name, VID and PID of every USB serial port
+pub fn list_usb_serial_ports() -> Result<Vec<(String, u16, u16)>, String> {
+    let available_ports = match serialport::available_ports() {
+        Ok(available_ports) => available_ports,
+        Err(e) => {
+            log::error!("Error while getting available ports: {}", e);
+            return Err(format!("Error while getting available ports: {}", e));
+        }
+    };
+
+    Ok(available_ports
+        .into_iter()
+        .filter_map(|port| match port.port_type {
+            serialport::SerialPortType::UsbPort(usb_port_info) => {
+                Some((port.port_name, usb_port_info.vid, usb_port_info.pid))
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+/// Returns whether the drive at `drive_path` went away before the timeout
+pub async fn wait_for_uf2_drive_unmount(
+    drive_path: &Path,
+    progress: &mut JobProgress,
+) -> Result<bool, String> {
+    let started_at = Instant::now();
+
+    while drive_path.join(UF2_INFO_FILE_NAME).exists() {
+        progress.check_cancelled()?;
+
+        if started_at.elapsed() > UF2_DRIVE_UNMOUNT_TIMEOUT {
+            return Ok(false);
+        }
+
+        tokio::time::sleep(UF2_DRIVE_POLL_INTERVAL).await;
+    }
+
+    log::info!("UF2 drive at {} unmounted", drive_path.display());
+
+    Ok(true)
+}
+
+/// Waits for a UF2 drive other than `existing_drive_paths` to be mounted, returning `None` on
+/// timeout
+pub async fn wait_for_new_uf2_drive(
+    existing_drive_paths: &HashSet<PathBuf>,
+    progress: &mut JobProgress,
+) -> Result<Option<PathBuf>, String> {
+    let started_at = Instant::now();
+
+    while started_at.elapsed() < UF2_DRIVE_MOUNT_TIMEOUT {
+        progress.check_cancelled()?;
+
+        if let Some(drive_path) = list_uf2_drive_paths()?
+            .into_iter()
+            .find(|drive_path| !existing_drive_paths.contains(drive_path))
+        {
+            log::info!("UF2 drive mounted at {}", drive_path.display());
+            return Ok(Some(drive_path));
+        }
+
+        progress.update(
+            started_at.elapsed().as_millis() as usize,
+            UF2_DRIVE_MOUNT_TIMEOUT.as_millis() as usize,
+        );
+
+        tokio::time::sleep(UF2_DRIVE_POLL_INTERVAL).await;
+    }
+
+    Ok(None)
+}
+
+/// Copies an erase image to the bootloader drive and waits for the device to come back to the
+/// bootloader with its flash wiped, returning the drive's new path
+///
+/// Erase images run from RAM, wipe the flash including the filesystem, and reboot straight
+/// back into the bootloader.
+pub async fn erase_uf2_device(
+    drive_path: &Path,
+    erase_file_path: &Path,
+    progress: &mut JobProgress,
+) -> Result<PathBuf, String> {
+    progress.start_stage(JobStage::Erase);
+
+    let erase_file_name = match erase_file_path.file_name() {
+        Some(erase_file_name) => erase_file_name,
+        None => {
+            log::error!(
+                "Erase image path {} has no file name",
+                erase_file_path.display()
+            );
+
+            return Err(format!(
+                "Erase image path {} has no file name",
+                erase_file_path.display()
+            ));
+        }
+    };
+
+    // Taken before copying, since a fast bootloader can be back before the unmount is seen.
+    // The device's own drive goes away first, so its path counts as new when it returns.
+    let existing_drive_paths: HashSet<PathBuf> = list_uf2_drive_paths()?
+        .into_iter()
+        .filter(|existing_drive_path| existing_drive_path != drive_path)
+        .collect();
+
+    log::info!(
+        "Copying erase image {} to {}",
+        erase_file_path.display(),
+        drive_path.display()
+    );
+
+    match tokio::fs::copy(erase_file_path, drive_path.join(erase_file_name)).await {
+        Ok(_) => (),
+        Err(e) => {
+            log::error!(
+                "Error while copying erase image to {}: {}",
+                drive_path.display(),
+                e
+            );
+
+            return Err(format!(
+                "Error while copying erase image to {}: {}",
+                drive_path.display(),
+                e
+            ));
+        }
+    };
+
+    if !wait_for_uf2_drive_unmount(drive_path, progress).await? {
+        log::error!(
+            "UF2 drive at {} did not run the erase image",
+            drive_path.display()
+        );
+
+        return Err(format!(
+            "The bootloader at {} did not run the erase image, which most likely doesn't match the board.",
+            drive_path.display()
+        ));
+    }
+
+    match wait_for_new_uf2_drive(&existing_drive_paths, progress).await? {
+        Some(drive_path) => {
+            log::info!("Device erased, bootloader back at {}", drive_path.display());
+            Ok(drive_path)
+        }
+        None => {
+            log::error!("No UF2 drive was mounted after erasing the device");
+
+            Err("The device was erased but did not return to the bootloader. Double-tap the reset button, then flash again without erasing.".to_string())
+        }
+    }
+}
+
+/// Waits for the bootloader to drop its drive and the firmware's serial port to appear,
+/// returning the port's name
+///
+/// `bootloader_ports` are the ports present while the drive was mounted, which can share a
+/// vendor ID and even a name with the firmware's port.
+pub async fn wait_for_uf2_reboot(
+    drive_path: &Path,
+    firmware_usb_ids: &FirmwareUsbIds,
+    bootloader_ports: &[(String, u16, u16)],
+    progress: &mut JobProgress,
+) -> Result<String, String> {
+    progress.start_stage(JobStage::Reboot);
+
+    // The bootloader only unmounts once it has accepted a complete image
+    if !wait_for_uf2_drive_unmount(drive_path, progress).await? {
+        log::error!(
+            "UF2 drive at {} was still mounted {:?} after writing firmware",
+            drive_path.display(),
+            UF2_DRIVE_UNMOUNT_TIMEOUT
+        );
+
+        return Err(format!(
+            "The bootloader at {} did not reboot within {} seconds of receiving the firmware. It most likely rejected the image, e.g. because it was built for a different board or chip family.",
+            drive_path.display(),
+            UF2_DRIVE_UNMOUNT_TIMEOUT.as_secs()
+        ));
+    }
+
+    if let Some(port_name) =
+        wait_for_firmware_port(firmware_usb_ids, bootloader_ports, progress).await?
+    {
+        return Ok(port_name);
+    }
+
+    log::error!(
+        "No serial port with USB IDs {:?} appeared within {:?} of the bootloader rebooting",
+        firmware_usb_ids,
+        FIRMWARE_PORT_TIMEOUT
+    );
+
+    Err(format!(
+        "The bootloader accepted the firmware and rebooted, but no serial port with vendor ID {:04x} appeared within {} seconds. The firmware may be crashing on boot; check the device's screen or LEDs, or reconnect it and try again.",
+        firmware_usb_ids.vid,
+        FIRMWARE_PORT_TIMEOUT.as_secs()
+    ))
+}
+
+/// Waits for the firmware's serial port to appear once the bootloader has rebooted, returning
+/// `None` on timeout
+pub async fn wait_for_firmware_port(
+    firmware_usb_ids: &FirmwareUsbIds,
+    bootloader_ports: &[(String, u16, u16)],
+    progress: &mut JobProgress,
+) -> Result<Option<String>, String> {
+    let started_at = Instant::now();
+
+    while started_at.elapsed() < FIRMWARE_PORT_TIMEOUT {
+        progress.check_cancelled()?;
+
+        let firmware_port = list_usb_serial_ports()?.into_iter().find(|port| {
+            let (_, vid, pid) = port;
+
+            match firmware_usb_ids.pid {
+                Some(firmware_pid) => *vid == firmware_usb_ids.vid && *pid == firmware_pid,
+                None => *vid == firmware_usb_ids.vid && !bootloader_ports.contains(port),
+            }
+        });
+
+        if let Some((port_name, vid, pid)) = firmware_port {
+            log::info!(
+                "Firmware came back on port {} ({:04x}:{:04x})",
+                port_name,
+                vid,
+                pid
+            );
+
+            return Ok(Some(port_name));
+        }
+
+        progress.update(
+            started_at.elapsed().as_millis() as usize,
+            FIRMWARE_PORT_TIMEOUT.as_millis() as usize,
+        );
+
+        tokio::time::sleep(UF2_DRIVE_POLL_INTERVAL).await;
+    }
+
+    Ok(None)
+}
+
+/// Asks the firmware on `serial_port` to reboot into its bootloader
+pub async fn touch_serial_port(serial_port: &String) -> Result<(), String> {
+    log::info!(
+        "Opening {} at {} baud to enter the UF2 bootloader",
+        serial_port,
+        BOOTLOADER_TOUCH_BAUD_RATE
+    );
+
+    let mut port = match serialport::new(serial_port, BOOTLOADER_TOUCH_BAUD_RATE).open() {
+        Ok(port) => port,
+        Err(e) => {
+            log::error!("Error while opening serial port {}: {}", serial_port, e);
+            return Err(format!(
+                "Error while opening serial port {}: {}",
+                serial_port, e
+            ));
+        }
+    };
+
+    // The device may already be rebooting, in which case the port is gone and that's fine
+    if let Err(e) = port.write_data_terminal_ready(false) {
+        log::warn!("Error while dropping DTR on {}: {}", serial_port, e);
+    }
+
+    tokio::time::sleep(BOOTLOADER_TOUCH_HOLD_TIME).await;
+
+    Ok(())
+}
+
+/// Resolves `upload_port` to a UF2 drive, touching it into the bootloader if it is a serial port
+///
+/// A directory is taken to be the drive itself, for boards already put into the bootloader by
+/// hand.
+pub async fn get_uf2_drive(
+    upload_port: &String,
+    progress: &mut JobProgress,
+) -> Result<PathBuf, String> {
+    if Path::new(upload_port).is_dir() {
+        return Ok(PathBuf::from(upload_port));
+    }
+
+    progress.start_stage(JobStage::Connect);
+
+    // Drives that were already mounted belong to some other device
+    let existing_drive_paths: HashSet<PathBuf> = list_uf2_drive_paths()?.into_iter().collect();
+
+    touch_serial_port(upload_port).await?;
+
+    if let Some(drive_path) = wait_for_new_uf2_drive(&existing_drive_paths, progress).await? {
+        return Ok(drive_path);
+    }
+
+    log::error!(
+        "No UF2 drive was mounted within {:?} of touching {}",
+        UF2_DRIVE_MOUNT_TIMEOUT,
+        upload_port
+    );
+
+    Err(format!(
+        "No UF2 drive was mounted within {} seconds of touching {}. Double-tap the reset button to enter the bootloader, make sure the drive is mounted, then select the drive as the upload port.",
+        UF2_DRIVE_MOUNT_TIMEOUT.as_secs(),
+        upload_port
+    ))
+}
+
+/// One 512-byte block of a UF2 image
+#[derive(Clone, Debug)]
+pub struct Uf2Block {
+    pub flags: u32,
+    pub target_address: u32,
+    pub block_number: u32,
+    pub block_count: u32,
+    pub family_id: Option<u32>, // Only when UF2_FLAG_FAMILY_ID_PRESENT is set
+    pub data: Vec<u8>,
+}
+
+fn read_u32_le(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// Splits a UF2 image into its blocks, rejecting anything that isn't a well-formed UF2 file
+pub fn parse_uf2_blocks(image: &[u8]) -> Result<Vec<Uf2Block>, String> {
+    if image.is_empty() || !image.len().is_multiple_of(UF2_BLOCK_SIZE) {
+        log::error!(
+            "UF2 image is {} bytes, not a multiple of the {} byte block size",
+            image.len(),
+            UF2_BLOCK_SIZE
+        );
+
+        return Err(format!(
+            "UF2 image is {} bytes, not a multiple of the {} byte block size",
+            image.len(),
+            UF2_BLOCK_SIZE
+        ));
+    }
+
+    image
+        .chunks(UF2_BLOCK_SIZE)
+        .enumerate()
+        .map(|(index, block)| {
+            if read_u32_le(block, 0) != UF2_MAGIC_START0
+                || read_u32_le(block, 4) != UF2_MAGIC_START1
+                || read_u32_le(block, UF2_BLOCK_SIZE - 4) != UF2_MAGIC_END
+            {
+                log::error!("UF2 block {} has invalid magic numbers", index);
+                return Err(format!("UF2 block {} has invalid magic numbers", index));
+            }
+
+            let payload_size = read_u32_le(block, 16) as usize;
+
+            if payload_size > UF2_MAX_PAYLOAD_SIZE {
+                log::error!(
+                    "UF2 block {} has a {} byte payload, more than the {} bytes a block holds",
+                    index,
+                    payload_size,
+                    UF2_MAX_PAYLOAD_SIZE
+                );
+
+                return Err(format!(
+                    "UF2 block {} has a {} byte payload, more than the {} bytes a block holds",
+                    index, payload_size, UF2_MAX_PAYLOAD_SIZE
+                ));
+            }
+
+            let flags = read_u32_le(block, 8);
+
+            Ok(Uf2Block {
+                flags,
+                target_address: read_u32_le(block, 12),
+                block_number: read_u32_le(block, 20),
+                block_count: read_u32_le(block, 24),
+                family_id: if flags & UF2_FLAG_FAMILY_ID_PRESENT != 0 {
+                    Some(read_u32_le(block, 28))
+                } else {
+                    None
+                },
+                data: block[32..32 + payload_size].to_vec(),
+            })
+        })
+        .collect()
+}
+
+/// Copies a UF2 image to the bootloader drive at `drive_path`, checking that all of it landed
+pub async fn copy_uf2_file(
+    firmware_file_name: String,
+    firmware_file_path: PathBuf,
+    drive_path: PathBuf,
+    progress: &mut JobProgress,
+) -> Result<(), String> {
+    // Open temporary firmware file
+
+    let firmware_file = match File::open(firmware_file_path.clone()).await {
+        Ok(firmware_file) => firmware_file,
+        Err(e) => {
+            log::error!(
+                "Error while opening firmware file at {}: {}",
+                firmware_file_path.display(),
+                e.to_string()
+            );
+
+            return Err(format!(
+                "Error while opening firmware file at {}: {}",
+                firmware_file_path.display(),
+                e
+            ));
+        }
+    };
+
+    log::info!("Opened firmware file at {}", firmware_file_path.display());
+
+    // Create output file
+
+    let output_file_path = drive_path.join(&firmware_file_name);
+
+    log::info!("Output file path: {}", output_file_path.display());
+
+    let output_file = match File::create(output_file_path.clone()).await {
+        Ok(output_file) => output_file,
+        Err(e) => {
+            log::error!(
+                "Error while creating output file at {}: {}",
+                output_file_path.display(),
+                e.to_string()
+            );
+
+            return Err(format!(
+                "Error while creating output file at {}: {}",
+                output_file_path.display(),
+                e
+            ));
+        }
+    };
+
+    log::info!("Opened output file at {}", output_file_path.display());
+
+    // Write contents of firmware file to output file; the copy is not a cancellation point,
+    // since the bootloader would be left holding a partial image
+
+    let firmware_file_size = match firmware_file.metadata().await {
+        Ok(metadata) => metadata.len() as usize,
+        Err(e) => {
+            log::error!(
+                "Error while reading metadata of firmware file at {}: {}",
+                firmware_file_path.display(),
+                e.to_string()
+            );
+
+            return Err(format!(
+                "Error while reading metadata of firmware file at {}: {}",
+                firmware_file_path.display(),
+                e
+            ));
+        }
+    };
+
+    let mut firmware_file_reader = tokio::io::BufReader::new(firmware_file);
+
+    log::info!("Created firmware file reader");
+
+    let mut output_file_writer = tokio::io::BufWriter::new(output_file);
+
+    log::info!("Created output file writer");
+
+    progress.plan_images(vec![firmware_file_size]);
+    progress.start_stage(JobStage::Write {
+        image_index: 1,
+        image_count: 1,
+        image_name: firmware_file_name.clone(),
+    });
+
+    let mut buffer = vec![0u8; UF2_COPY_CHUNK_SIZE];
+    let mut bytes_copied = 0;
+
+    loop {
+        let bytes_read = match firmware_file_reader.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(e) => {
+                log::error!(
+                    "Error while reading firmware file at {}: {}",
+                    firmware_file_path.display(),
+                    e.to_string()
+                );
+
+                return Err(format!(
+                    "Error while reading firmware file at {}: {}",
+                    firmware_file_path.display(),
+                    e
+                ));
+            }
+        };
+
+        match output_file_writer.write_all(&buffer[..bytes_read]).await {
+            Ok(_) => (),
+            Err(e) => {
+                log::error!(
+                    "Error while copying firmware file to output file at {}: {}",
+                    output_file_path.display(),
+                    e.to_string()
+                );
+
+                return Err(format!(
+                    "Error while copying firmware file to output file at {}: {}",
+                    output_file_path.display(),
+                    e
+                ));
+            }
+        };
+
+        bytes_copied += bytes_read;
+        progress.update(bytes_copied, firmware_file_size);
+    }
+
+    if bytes_copied != firmware_file_size {
+        log::error!(
+            "Copied {} bytes of {} byte firmware file to {}",
+            bytes_copied,
+            firmware_file_size,
+            output_file_path.display()
+        );
+
+        return Err(format!(
+            "Copied {} bytes of {} byte firmware file to {}",
+            bytes_copied,
+            firmware_file_size,
+            output_file_path.display()
+        ));
+    }
+
+    // The bootloader only flashes once the whole file has landed on the drive
+    match output_file_writer.flush().await {
+        Ok(_) => (),
+        Err(e) => {
+            log::error!(
+                "Error while flushing output file at {}: {}",
+                output_file_path.display(),
+                e.to_string()
+            );
+
+            return Err(format!(
+                "Error while flushing output file at {}: {}",
+                output_file_path.display(),
+                e
+            ));
+        }
+    };
+
+    // The bootloader reboots as soon as it has the last block, which can unmount the drive
+    // before the sync returns; that only counts as a failure if the drive is still there
+    let drive_mounted = || drive_path.join(UF2_INFO_FILE_NAME).exists();
+
+    match output_file_writer.into_inner().sync_all().await {
+        Ok(_) => (),
+        Err(e) if !drive_mounted() => {
+            log::warn!(
+                "UF2 drive unmounted while syncing {}, the bootloader most likely already has the image: {}",
+                output_file_path.display(),
+                e
+            );
+        }
+        Err(e) => {
+            log::error!(
+                "Error while syncing output file at {}: {}",
+                output_file_path.display(),
+                e.to_string()
+            );
+
+            return Err(format!(
+                "Error while syncing output file at {}: {}",
+                output_file_path.display(),
+                e
+            ));
+        }
+    };
+
+    // Some bootloaders hide the file once it has been flashed, so only a file of the wrong
+    // size is a failure
+    if drive_mounted() {
+        if let Ok(metadata) = tokio::fs::metadata(&output_file_path).await {
+            if metadata.len() as usize != firmware_file_size {
+                log::error!(
+                    "Firmware file on UF2 drive is {} bytes, expected {}",
+                    metadata.len(),
+                    firmware_file_size
+                );
+
+                return Err(format!(
+                    "Firmware file on UF2 drive is {} bytes, expected {}",
+                    metadata.len(),
+                    firmware_file_size
+                ));
+            }
+        }
+    }
+
+    log::info!("Wrote firmware file to {}", output_file_path.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{build_boards, build_rp2040_uf2_blocks, build_uf2_image};
+
+    // As written by the Adafruit nRF52 bootloader
+    const NRF52_INFO_UF2: &str = "UF2 Bootloader 0.6.1 lib/nrfx (v2.0.0) lib/tinyusb (0.10.1-41-gdf0cda2d) lib/uf2 (remotes/origin/configupdate-9-gadbb8c7)\r\n\
+Model: RAK4631\r\n\
+Board-ID: nRF52840-RAK4631-Rev1\r\n\
+SoftDevice: S140 version 6.1.1\r\n\
+Date: Dec 21 2021\r\n";
+
+    // As written by the RP2040 boot ROM
+    const RP2040_INFO_UF2: &str =
+        "UF2 Bootloader v3.0\nModel: Raspberry Pi RP2\nBoard-ID: RPI-RP2\n";
+
+    #[test]
+    fn parses_nrf52_bootloader_info() {
+        let info = parse_uf2_bootloader_info(NRF52_INFO_UF2);
+
+        assert_eq!(info.bootloader_version.as_deref(), Some("0.6.1"));
+        assert_eq!(info.model.as_deref(), Some("RAK4631"));
+        assert_eq!(info.board_id.as_deref(), Some("nRF52840-RAK4631-Rev1"));
+        assert_eq!(info.soft_device.as_deref(), Some("S140 version 6.1.1"));
+        assert_eq!(info.date.as_deref(), Some("Dec 21 2021"));
+    }
+
+    #[test]
+    fn strips_leading_v_from_bootloader_version() {
+        let info = parse_uf2_bootloader_info(RP2040_INFO_UF2);
+
+        assert_eq!(info.bootloader_version.as_deref(), Some("3.0"));
+        assert_eq!(info.board_id.as_deref(), Some("RPI-RP2"));
+        assert_eq!(info.soft_device, None);
+    }
+
+    #[test]
+    fn ignores_unknown_and_malformed_lines() {
+        let info = parse_uf2_bootloader_info("Not a bootloader\nFlash-Size: 1MB\n\n");
+
+        assert_eq!(info.bootloader_version, None);
+        assert_eq!(info.model, None);
+        assert_eq!(info.board_id, None);
+    }
+
+    #[test]
+    fn matches_boards_named_by_bootloader() {
+        let boards = build_boards();
+        let info = parse_uf2_bootloader_info(NRF52_INFO_UF2);
+
+        let hw_models: Vec<u32> = get_matching_boards(&info, &boards)
+            .iter()
+            .map(|board| board.hw_model)
+            .collect();
+
+        assert_eq!(hw_models, vec![9]);
+    }
+
+    #[test]
+    fn falls_back_to_every_board_of_the_architecture() {
+        let boards = build_boards();
+        let info = parse_uf2_bootloader_info(
+            "UF2 Bootloader 0.6.1\nModel: Unknown\nBoard-ID: nRF52840-Unknown\n",
+        );
+
+        let hw_models: Vec<u32> = get_matching_boards(&info, &boards)
+            .iter()
+            .map(|board| board.hw_model)
+            .collect();
+
+        assert_eq!(hw_models, vec![9, 7]);
+    }
+
+    #[test]
+    fn matches_rp2040_boot_rom() {
+        let boards = build_boards();
+        let info = parse_uf2_bootloader_info(RP2040_INFO_UF2);
+
+        let hw_models: Vec<u32> = get_matching_boards(&info, &boards)
+            .iter()
+            .map(|board| board.hw_model)
+            .collect();
+
+        assert_eq!(hw_models, vec![47]);
+    }
+
+    #[test]
+    fn matches_nothing_for_unknown_bootloaders() {
+        let boards = build_boards();
+        let info = parse_uf2_bootloader_info("UF2 Bootloader 1.0\nBoard-ID: SAMD21-Feather\n");
+
+        assert!(get_matching_boards(&info, &boards).is_empty());
+    }
+
+    #[test]
+    fn parses_uf2_blocks() {
+        let blocks = parse_uf2_blocks(&build_uf2_image(&build_rp2040_uf2_blocks(3))).unwrap();
+
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[2].block_number, 2);
+        assert_eq!(blocks[2].block_count, 3);
+        assert_eq!(blocks[2].target_address, 0x1000_0200);
+        assert_eq!(blocks[2].family_id, Some(0xE48B_FF56));
+        assert_eq!(blocks[2].data, vec![0xA5; 256]);
+    }
+
+    #[test]
+    fn ignores_family_id_without_flag() {
+        let mut blocks = build_rp2040_uf2_blocks(1);
+        blocks[0].flags = 0;
+
+        let mut image = build_uf2_image(&blocks);
+        image[28..32].copy_from_slice(&0xE48B_FF56u32.to_le_bytes());
+
+        assert_eq!(parse_uf2_blocks(&image).unwrap()[0].family_id, None);
+    }
+
+    #[test]
+    fn rejects_partial_and_empty_images() {
+        let image = build_uf2_image(&build_rp2040_uf2_blocks(2));
+
+        assert!(parse_uf2_blocks(&image[..UF2_BLOCK_SIZE + 100]).is_err());
+        assert!(parse_uf2_blocks(&[]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut image = build_uf2_image(&build_rp2040_uf2_blocks(2));
+        image[2 * UF2_BLOCK_SIZE - 1] ^= 0xFF;
+
+        assert!(parse_uf2_blocks(&image).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_payload() {
+        let mut image = build_uf2_image(&build_rp2040_uf2_blocks(1));
+        image[16..20].copy_from_slice(&(UF2_MAX_PAYLOAD_SIZE as u32 + 1).to_le_bytes());
+
+        assert!(parse_uf2_blocks(&image).is_err());
+    }
+}
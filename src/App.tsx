@@ -298,9 +298,11 @@ const App = () => {
       setFlashStates((prev) => ({ ...prev, [port]: "pending" }));
 
       await invoke("flash_device", {
-        hwModel: board.selectedHwModel,
-        uploadPort: board.selectedPort,
-        firmwareVersionId: board.selectedFirmwareVersion,
+        request: {
+          hwModel: board.selectedHwModel,
+          uploadPort: board.selectedPort,
+          firmwareVersionId: board.selectedFirmwareVersion,
+        },
       });
 
       setFlashStates((prev) => ({ ...prev, [port]: "success" }));
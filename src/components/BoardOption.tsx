@@ -66,12 +66,12 @@ BoardOptionProps) => {
     async function setupListener() {
       const unlisten = await listen<{
         boardId: string;
-        current: number;
-        total: number;
-      }>(`flash-status-update-${boardOptionData.selectedPort}`, (event) => {
-        const { boardId, current, total } = event.payload;
+        stage: { type: string };
+        overallPercent: number;
+      }>(`flash-job-progress-${boardOptionData.selectedPort}`, (event) => {
+        const { boardId, overallPercent } = event.payload;
         info(`Received event from "${boardId}": ${JSON.stringify(event)}`);
-        setProgress((current / total) * 100);
+        setProgress(overallPercent);
       });
 
       cleanupFn = unlisten;